@@ -19,8 +19,11 @@ import {
   findDaisychainDiscount,
   createStoreCreditDiscount,
   findStoreCreditDiscount,
+  ensureStoreCreditDiscountConfig,
+  syncStoreCreditQueryVariables,
   DEFAULT_CONFIG,
 } from "./discount-config";
+import { saveShopCurrency } from "./shopify-queries";
 
 /**
  * Validate that a discount ID is actually an automatic app discount
@@ -74,7 +77,10 @@ async function validateDiscountType(
  * Steps:
  * 1. Query for function ID if not stored
  * 2. Create discount if it doesn't exist
- * 3. Store IDs in database
+ * 3. Create the store credit discount if it doesn't exist, or migrate its config,
+ *    and copy the referral discount's excluded tags to it
+ * 4. Save the shop's currency for the discount function
+ * IDs are stored in the database as they're found
  */
 export async function ensureAppSetup(
   admin: AdminApiContext,
@@ -189,6 +195,25 @@ export async function ensureAppSetup(
     console.log(`[Setup] ✅ Store credit discount ID already exists: ${storeCreditDiscountId}`);
   }
 
+  // Store credit discounts created before they had a config get one
+  if (storeCreditDiscountId && !(await ensureStoreCreditDiscountConfig(admin, storeCreditDiscountId))) {
+    console.error(`[Setup] ❌ Failed to save the store credit discount's config`);
+  }
+
+  // Store credit leaves out the same tagged products as the referral discount
+  if (
+    discountId &&
+    storeCreditDiscountId &&
+    !(await syncStoreCreditQueryVariables(admin, discountId, storeCreditDiscountId))
+  ) {
+    console.error(`[Setup] ❌ Failed to copy excluded tags to the store credit discount`);
+  }
+
+  // Step 4: Save the shop's currency (changing it later is picked up on the next setup)
+  if (!(await saveShopCurrency(admin))) {
+    console.warn(`[Setup] ⚠️ Could not save shop currency, store credit marked with a currency other than the cart's is left out`);
+  }
+
   return { functionId, discountId, storeCreditDiscountId };
 }
 
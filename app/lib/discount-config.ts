@@ -5,14 +5,25 @@
 
 import type { AdminApiContext } from "@shopify/shopify-app-react-router/server";
 import { getDiscountConfig, updateDiscountConfig } from "./shopify-queries";
+import { signingKeyMetafield } from "./referral-token";
 
 const METAFIELD_NAMESPACE = "$app:daisychain";
 
+// Which discount a config drives; the function treats configs without a mode as
+// referral configs (and logs it). The settings page only edits the referral discount
+export type DiscountMode = "referral" | "store_credit" | "combined";
+
 export interface DiscountConfig {
+  mode?: DiscountMode;
   referee_discount_percentage: number;
   referee_min_order: number;
   referrer_credit_amount: number;
   min_referrer_orders: number;
+  // Campaign hours in the shop's time zone (0-24, wrapping past midnight when end < start)
+  // The function checks them through the activeStartTime / activeEndTime query variables,
+  // and ignores equal hours and hours the variables weren't updated for
+  active_start_hour?: number;
+  active_end_hour?: number;
   // Referee offer settings
   referee_available_after_days: number; // 0 = immediate
   referee_redeemable_as_store_credit: boolean;
@@ -30,6 +41,7 @@ export interface DiscountConfig {
 }
 
 export const DEFAULT_CONFIG: DiscountConfig = {
+  mode: "referral",
   referee_discount_percentage: 10,
   referee_min_order: 0,
   referrer_credit_amount: 5.0,
@@ -50,6 +62,57 @@ export const DEFAULT_CONFIG: DiscountConfig = {
   email_notifications_enabled: true,
 };
 
+// The store credit discount's config: the function reads its mode, so adding
+// other settings to it never turns it into a referral discount
+export const STORE_CREDIT_CONFIG = { mode: "store_credit" as DiscountMode };
+
+// Store credit rules, in the store credit discount's store_credit_config metafield
+// (amounts in the shop's currency; unset limits mean no limit)
+export interface StoreCreditRules {
+  min_subtotal: number; // Eligible subtotal required before credit can be redeemed
+  max_amount_per_order?: number; // Most credit redeemable on one order
+  max_order_percentage?: number; // Share of the eligible subtotal credit may cover (0-100)
+}
+
+export const DEFAULT_STORE_CREDIT_RULES: StoreCreditRules = {
+  min_subtotal: 0,
+};
+
+// Input query variables for the function, stored in the discount's query_variables metafield
+export interface QueryVariables {
+  eligibleCollections: string[]; // Collection GIDs whose products qualify for product-target referrals
+  excludedTags: string[]; // Product tags excluded from referral discounts and store credit (set on
+  // the referral discount; the app copies them to the store credit discount)
+  activeStartTime: string; // Earlier bound of the campaign hours ("HH:MM:SS")
+  activeEndTime: string; // Later bound of the campaign hours ("HH:MM:SS")
+}
+
+export const DEFAULT_QUERY_VARIABLES: QueryVariables = {
+  eligibleCollections: [],
+  excludedTags: [],
+  activeStartTime: "00:00:00",
+  activeEndTime: "23:59:59",
+};
+
+/**
+ * Query variables for the config's campaign hours
+ * The input query checks one timeBetween window, so the bounds are sorted; the
+ * function treats windows that wrap past midnight as running outside them
+ */
+export function activeHoursVariables(
+  config: Pick<DiscountConfig, "active_start_hour" | "active_end_hour">,
+): Pick<QueryVariables, "activeStartTime" | "activeEndTime"> {
+  const start = config.active_start_hour ?? 0;
+  const end = config.active_end_hour ?? 24;
+  const time = (hour: number) =>
+    hour >= 24 ? "23:59:59" : `${String(Math.max(0, hour)).padStart(2, "0")}:00:00`;
+
+  return {
+    activeStartTime: time(Math.min(start, end)),
+    activeEndTime: time(Math.max(start, end)),
+  };
+}
+
 /**
  * Find the Daisychain discount by function ID
  * Returns discount ID if found, null otherwise
@@ -143,6 +206,14 @@ export async function createDaisychainDiscount(
         type: "json",
         value: JSON.stringify(config),
       },
+      {
+        namespace: METAFIELD_NAMESPACE,
+        key: "query_variables",
+        type: "json",
+        value: JSON.stringify({ ...DEFAULT_QUERY_VARIABLES, ...activeHoursVariables(config) }),
+      },
+      // Signs the referral_token cart attribute issued by the app proxy
+      signingKeyMetafield(),
     ],
   };
 
@@ -226,6 +297,21 @@ export async function createStoreCreditDiscount(
     discountClasses: ["ORDER"],
     appliesOnOneTimePurchase: true,
     appliesOnSubscription: true, // CRITICAL: Enable for subscriptions
+    metafields: [
+      {
+        namespace: METAFIELD_NAMESPACE,
+        key: "config",
+        type: "json",
+        value: JSON.stringify(STORE_CREDIT_CONFIG),
+      },
+      // Excluded tags are copied from the referral discount during setup
+      {
+        namespace: METAFIELD_NAMESPACE,
+        key: "query_variables",
+        type: "json",
+        value: JSON.stringify(DEFAULT_QUERY_VARIABLES),
+      },
+    ],
   };
 
   // Use functionHandle if available (preferred), otherwise fall back to functionId
@@ -268,6 +354,138 @@ export async function createStoreCreditDiscount(
   return null;
 }
 
+/**
+ * Give a store credit discount created without a config its store_credit mode
+ * (the function only falls back to store credit, with a log, for discounts
+ * without a config)
+ */
+export async function ensureStoreCreditDiscountConfig(
+  admin: AdminApiContext,
+  discountId: string,
+): Promise<boolean> {
+  const config = (await getDiscountConfig(admin, discountId)) as { mode?: DiscountMode } | null;
+
+  if (config?.mode === "store_credit") {
+    return true;
+  }
+
+  if (config?.mode) {
+    console.warn(`[Store Credit Discount] ⚠️ Config has mode "${config.mode}", resetting it to store_credit`);
+  } else {
+    console.log(`[Store Credit Discount] Migrating ${discountId} to a store_credit config`);
+  }
+
+  return setDiscountMetafields(admin, discountId, [
+    { key: "config", value: { ...config, ...STORE_CREDIT_CONFIG } },
+  ]);
+}
+
+/**
+ * Load the store credit discount's rules
+ */
+export async function loadStoreCreditRules(
+  admin: AdminApiContext,
+  discountId: string,
+): Promise<StoreCreditRules> {
+  const query = `#graphql
+    query GetStoreCreditRules($id: ID!, $namespace: String!, $key: String!) {
+      discountNode(id: $id) {
+        metafield(namespace: $namespace, key: $key) {
+          value
+        }
+      }
+    }
+  `;
+
+  const response = await admin.graphql(query, {
+    variables: { id: discountId, namespace: METAFIELD_NAMESPACE, key: "store_credit_config" },
+  });
+  const data = await response.json();
+
+  try {
+    const value = data.data?.discountNode?.metafield?.value;
+    return value ? { ...DEFAULT_STORE_CREDIT_RULES, ...JSON.parse(value) } : DEFAULT_STORE_CREDIT_RULES;
+  } catch {
+    // Unreadable rules are shown (and saved) as the defaults
+    return DEFAULT_STORE_CREDIT_RULES;
+  }
+}
+
+/**
+ * Save the store credit discount's rules, along with its store_credit mode
+ */
+export async function saveStoreCreditConfig(
+  admin: AdminApiContext,
+  discountId: string,
+  rules: StoreCreditRules,
+): Promise<boolean> {
+  const config = (await getDiscountConfig(admin, discountId)) as Record<string, unknown> | null;
+
+  return setDiscountMetafields(admin, discountId, [
+    { key: "config", value: { ...config, ...STORE_CREDIT_CONFIG } },
+    { key: "store_credit_config", value: rules },
+  ]);
+}
+
+/**
+ * Copy the referral discount's excludedTags to the store credit discount's
+ * query variables, so both discounts leave out the same products
+ * The store credit discount has no campaign hours or eligible collections
+ */
+export async function syncStoreCreditQueryVariables(
+  admin: AdminApiContext,
+  referralDiscountId: string,
+  storeCreditDiscountId: string,
+): Promise<boolean> {
+  const { excludedTags } = await loadQueryVariables(admin, referralDiscountId);
+
+  return setDiscountMetafields(admin, storeCreditDiscountId, [
+    { key: "query_variables", value: { ...DEFAULT_QUERY_VARIABLES, excludedTags } },
+  ]);
+}
+
+/**
+ * Write JSON metafields on a discount
+ */
+async function setDiscountMetafields(
+  admin: AdminApiContext,
+  discountId: string,
+  metafields: Array<{ key: string; value: unknown }>,
+): Promise<boolean> {
+  const mutation = `#graphql
+    mutation SetDiscountMetafields($metafields: [MetafieldsSetInput!]!) {
+      metafieldsSet(metafields: $metafields) {
+        userErrors {
+          field
+          message
+        }
+      }
+    }
+  `;
+
+  const response = await admin.graphql(mutation, {
+    variables: {
+      metafields: metafields.map(({ key, value }) => ({
+        ownerId: discountId,
+        namespace: METAFIELD_NAMESPACE,
+        key,
+        type: "json",
+        value: JSON.stringify(value),
+      })),
+    },
+  });
+
+  const data = await response.json();
+  const errors = data.data?.metafieldsSet?.userErrors || [];
+
+  if (errors.length > 0) {
+    console.error("[Discount Config] Metafield set errors:", errors);
+    return false;
+  }
+
+  return true;
+}
+
 /**
  * Find the store credit discount by function ID
  */
@@ -357,13 +575,114 @@ export async function getOrCreateDaisychainDiscount(
 
 /**
  * Update discount configuration
+ * Also keeps the campaign hours in the query variables in step with the config
  */
 export async function saveDiscountConfig(
   admin: AdminApiContext,
   discountId: string,
   config: DiscountConfig,
 ): Promise<boolean> {
-  return await updateDiscountConfig(admin, discountId, config);
+  warnIgnoredHours(config);
+  const saved = await updateDiscountConfig(admin, discountId, config);
+  return saved && (await saveActiveHoursVariables(admin, discountId, config));
+}
+
+/**
+ * Warn about campaign hours the function ignores (it logs them too)
+ */
+function warnIgnoredHours(config: DiscountConfig) {
+  if (config.active_start_hour !== undefined && config.active_start_hour === config.active_end_hour) {
+    console.warn(
+      `[Discount Config] ⚠️ active_start_hour and active_end_hour are both ${config.active_start_hour}, the campaign runs all day`,
+    );
+  }
+
+  const programs = (config as { programs?: Record<string, Partial<DiscountConfig>> }).programs ?? {};
+  for (const [name, program] of Object.entries(programs)) {
+    if (program.active_start_hour !== undefined || program.active_end_hour !== undefined) {
+      console.warn(
+        `[Discount Config] ⚠️ Program "${name}" sets active hours, only the top-level hours are used`,
+      );
+    }
+  }
+}
+
+/**
+ * Read a discount's query_variables metafield
+ * Unreadable variables are replaced with the defaults
+ */
+async function loadQueryVariables(
+  admin: AdminApiContext,
+  discountId: string,
+): Promise<QueryVariables> {
+  const query = `#graphql
+    query GetQueryVariables($id: ID!, $namespace: String!, $key: String!) {
+      discountNode(id: $id) {
+        metafield(namespace: $namespace, key: $key) {
+          value
+        }
+      }
+    }
+  `;
+
+  const response = await admin.graphql(query, {
+    variables: { id: discountId, namespace: METAFIELD_NAMESPACE, key: "query_variables" },
+  });
+  const data = await response.json();
+
+  try {
+    const value = data.data?.discountNode?.metafield?.value;
+    return value ? { ...DEFAULT_QUERY_VARIABLES, ...JSON.parse(value) } : DEFAULT_QUERY_VARIABLES;
+  } catch {
+    return DEFAULT_QUERY_VARIABLES;
+  }
+}
+
+/**
+ * Write the config's campaign hours to the query_variables metafield,
+ * keeping the other variables
+ */
+async function saveActiveHoursVariables(
+  admin: AdminApiContext,
+  discountId: string,
+  config: DiscountConfig,
+): Promise<boolean> {
+  const queryVariables = await loadQueryVariables(admin, discountId);
+
+  const mutation = `#graphql
+    mutation SetQueryVariables($metafields: [MetafieldsSetInput!]!) {
+      metafieldsSet(metafields: $metafields) {
+        userErrors {
+          field
+          message
+        }
+      }
+    }
+  `;
+
+  const setResponse = await admin.graphql(mutation, {
+    variables: {
+      metafields: [
+        {
+          ownerId: discountId,
+          namespace: METAFIELD_NAMESPACE,
+          key: "query_variables",
+          type: "json",
+          value: JSON.stringify({ ...queryVariables, ...activeHoursVariables(config) }),
+        },
+      ],
+    },
+  });
+
+  const setData = await setResponse.json();
+  const errors = setData.data?.metafieldsSet?.userErrors || [];
+
+  if (errors.length > 0) {
+    console.error("Query variables update errors:", errors);
+    return false;
+  }
+
+  return true;
 }
 
 /**
@@ -0,0 +1,252 @@
+/**
+ * Signed referral tokens
+ * The app proxy signs the referrer it looked up, their order count, email
+ * hash and referral program and the shop's local date; the discount function
+ * only applies link referrals whose referral_token cart attribute checks out,
+ * checks min_referrer_orders and referee_available_after_days against the
+ * signed values, turns away guests checking out with the referrer's email and
+ * only applies the program the referrer belongs to
+ */
+
+import type { AdminApiContext } from "@shopify/shopify-app-react-router/server";
+import { createHash, createHmac, randomBytes } from "node:crypto";
+import { getDiscountConfig } from "./shopify-queries";
+
+const METAFIELD_NAMESPACE = "$app:daisychain";
+const SIGNING_KEY_METAFIELD = "referral_signing_key";
+
+// Days a token stays valid once the referee discount is available; the shopper
+// has this long to check out
+const TOKEN_VALID_DAYS = 30;
+
+/**
+ * Generate a new signing key (hex, 256 bits)
+ */
+export function generateSigningKey(): string {
+  return randomBytes(32).toString("hex");
+}
+
+/**
+ * Metafield input for the discount's signing key, used when creating the discount
+ */
+export function signingKeyMetafield(signingKey: string = generateSigningKey()) {
+  return {
+    namespace: METAFIELD_NAMESPACE,
+    key: SIGNING_KEY_METAFIELD,
+    type: "single_line_text_field",
+    value: signingKey,
+  };
+}
+
+/**
+ * Hex SHA-256 of a trimmed, lowercased email ("" without an email)
+ */
+export function emailHash(email: string | null | undefined): string {
+  const normalized = (email || "").trim().toLowerCase();
+  return normalized ? createHash("sha256").update(normalized).digest("hex") : "";
+}
+
+/**
+ * Sign a referral for the referral_token cart attribute
+ * Format: <expires_on>.<order_count>.<referred_on>.<email_hash>.<referral_program>.<hex
+ * HMAC-SHA256 of "referrer_id|expires_on|order_count|referred_on|email_hash|referral_program">,
+ * dates in the shop's time zone (must match referral_token.rs in the discount function)
+ */
+export function signReferralToken(
+  referrerId: string,
+  referrerOrderCount: number,
+  referrerEmail: string | null | undefined,
+  referralProgram: string,
+  referredOn: string,
+  expiresOn: string,
+  signingKey: string,
+): string {
+  const orderCount = String(Math.max(0, Math.floor(Number(referrerOrderCount) || 0)));
+  const referrerEmailHash = emailHash(referrerEmail);
+
+  const signature = createHmac("sha256", signingKey)
+    .update(`${referrerId}|${expiresOn}|${orderCount}|${referredOn}|${referrerEmailHash}|${referralProgram}`)
+    .digest("hex");
+
+  return `${expiresOn}.${orderCount}.${referredOn}.${referrerEmailHash}.${referralProgram}.${signature}`;
+}
+
+/**
+ * Today's ISO date (2025-01-31) in an IANA time zone
+ */
+export function localDate(timeZone: string, now: Date = new Date()): string {
+  const parts = new Intl.DateTimeFormat("en-US", {
+    timeZone,
+    year: "numeric",
+    month: "2-digit",
+    day: "2-digit",
+  }).formatToParts(now);
+  const part = (type: string) => parts.find((p) => p.type === type)?.value;
+
+  return `${part("year")}-${part("month")}-${part("day")}`;
+}
+
+/**
+ * The ISO date `days` after an ISO date
+ */
+export function addDays(date: string, days: number): string {
+  const [year, month, day] = date.split("-").map(Number);
+  return new Date(Date.UTC(year, month - 1, day + days)).toISOString().slice(0, 10);
+}
+
+/**
+ * The shop's IANA time zone (the discount function compares dates in it)
+ */
+async function getShopTimezone(admin: AdminApiContext): Promise<string> {
+  const query = `#graphql
+    query GetShopTimezone {
+      shop {
+        ianaTimezone
+      }
+    }
+  `;
+
+  const response = await admin.graphql(query);
+  const data = await response.json();
+
+  return data.data?.shop?.ianaTimezone || "UTC";
+}
+
+/**
+ * Read the discount's signing key
+ */
+async function getReferralSigningKey(
+  admin: AdminApiContext,
+  discountId: string,
+): Promise<string | null> {
+  const query = `#graphql
+    query GetReferralSigningKey($id: ID!, $namespace: String!, $key: String!) {
+      discountNode(id: $id) {
+        metafield(namespace: $namespace, key: $key) {
+          value
+        }
+      }
+    }
+  `;
+
+  const response = await admin.graphql(query, {
+    variables: {
+      id: discountId,
+      namespace: METAFIELD_NAMESPACE,
+      key: SIGNING_KEY_METAFIELD,
+    },
+  });
+
+  const data = await response.json();
+  return data.data?.discountNode?.metafield?.value || null;
+}
+
+/**
+ * Get the discount's signing key, creating it for discounts made before keys existed
+ * Returns null if the key can't be read or written
+ */
+export async function ensureReferralSigningKey(
+  admin: AdminApiContext,
+  discountId: string,
+): Promise<string | null> {
+  const existingKey = await getReferralSigningKey(admin, discountId);
+
+  if (existingKey) {
+    return existingKey;
+  }
+
+  const mutation = `#graphql
+    mutation SetReferralSigningKey($metafields: [MetafieldsSetInput!]!) {
+      metafieldsSet(metafields: $metafields) {
+        userErrors {
+          field
+          message
+          code
+        }
+      }
+    }
+  `;
+
+  // A null compareDigest only writes the key if there still is none, so two
+  // lookups creating it at once can't replace each other's key
+  const signingKey = generateSigningKey();
+  const setResponse = await admin.graphql(mutation, {
+    variables: {
+      metafields: [
+        { ownerId: discountId, ...signingKeyMetafield(signingKey), compareDigest: null },
+      ],
+    },
+  });
+
+  const setData = await setResponse.json();
+  const errors = setData.data?.metafieldsSet?.userErrors || [];
+
+  if (errors.some((error: { code?: string }) => error.code === "STALE_OBJECT")) {
+    // Another request created the key first; use theirs
+    return getReferralSigningKey(admin, discountId);
+  }
+
+  if (errors.length > 0) {
+    console.error("Referral signing key errors:", errors);
+    return null;
+  }
+
+  console.log(`[Referral Token] Created signing key for discount: ${discountId}`);
+  return signingKey;
+}
+
+// What every token issued for a request shares
+export interface ReferralTokenSigning {
+  signingKey: string;
+  referredOn: string;
+  expiresOn: string;
+}
+
+/**
+ * Resolve the signing key and dates for the shop's referral discount, referred today (shop time)
+ * Call once per request and sign each referrer with issueReferralToken
+ * Returns null when the shop has no discount yet (the function then skips token checks)
+ */
+export async function getReferralTokenSigning(
+  admin: AdminApiContext,
+  discountId: string | null | undefined,
+): Promise<ReferralTokenSigning | null> {
+  if (!discountId) {
+    return null;
+  }
+
+  const signingKey = await ensureReferralSigningKey(admin, discountId);
+  if (!signingKey) {
+    return null;
+  }
+
+  const [timeZone, config] = await Promise.all([
+    getShopTimezone(admin),
+    getDiscountConfig(admin, discountId),
+  ]);
+
+  // The token has to outlast the referee_available_after_days wait
+  const referredOn = localDate(timeZone);
+  const waitDays = Math.max(0, config?.referee_available_after_days ?? 0);
+  const expiresOn = addDays(referredOn, waitDays + TOKEN_VALID_DAYS);
+
+  return { signingKey, referredOn, expiresOn };
+}
+
+/**
+ * Sign a referral with the request's signing key and dates
+ */
+export function issueReferralToken(
+  signing: ReferralTokenSigning,
+  referrer: { id: string; numberOfOrders: number; email: string; referralProgram: string },
+): string {
+  return signReferralToken(
+    referrer.id,
+    referrer.numberOfOrders,
+    referrer.email,
+    referrer.referralProgram,
+    signing.referredOn,
+    signing.expiresOn,
+    signing.signingKey,
+  );
+}
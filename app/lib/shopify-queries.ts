@@ -170,6 +170,8 @@ export async function findCustomersByName(
   id: string;
   email: string;
   displayName: string;
+  numberOfOrders: number;
+  referralProgram: string;
 }>> {
   // Split name into first and last (simple approach)
   const nameParts = name.trim().split(/\s+/);
@@ -195,6 +197,10 @@ export async function findCustomersByName(
             }
             displayName
             numberOfOrders
+            # Named program the referrer belongs to ("influencer", "employee"), set by the merchant
+            referralProgram: metafield(namespace: "$app:daisychain", key: "referral_program") {
+              value
+            }
             orders(first: 1) {
               edges {
                 node {
@@ -239,6 +245,8 @@ export async function findCustomersByName(
       id: string;
       email: string;
       displayName: string;
+      numberOfOrders: number;
+      referralProgram: string;
     }> = [];
     
     console.log(`[findCustomersByName] Searching for: "${name}"`);
@@ -264,6 +272,9 @@ export async function findCustomersByName(
           id: customer.id,
           email: customer.defaultEmailAddress?.emailAddress || "",
           displayName: customer.displayName || name,
+          // numberOfOrders may be stale, but a customer with orders has at least one
+          numberOfOrders: Math.max(numberOfOrders, hasOrders ? 1 : 0),
+          referralProgram: customer.referralProgram?.value?.trim() || "",
         });
       }
     }
@@ -310,15 +321,21 @@ export async function getCustomerById(
   id: string;
   email: string;
   displayName: string;
+  numberOfOrders: number;
+  referralProgram: string;
 } | null> {
   const query = `#graphql
     query GetCustomerById($id: ID!) {
       customer(id: $id) {
         id
         displayName
+        numberOfOrders
         defaultEmailAddress {
           emailAddress
         }
+        referralProgram: metafield(namespace: "$app:daisychain", key: "referral_program") {
+          value
+        }
       }
     }
   `;
@@ -346,6 +363,8 @@ export async function getCustomerById(
       id: customer.id,
       email: customer.defaultEmailAddress?.emailAddress || "",
       displayName: customer.displayName || "",
+      numberOfOrders: Number(customer.numberOfOrders) || 0,
+      referralProgram: customer.referralProgram?.value?.trim() || "",
     };
   } catch (error) {
     console.error("Error in getCustomerById:", error);
@@ -476,6 +495,66 @@ export async function setOrderMetafield(
   return true;
 }
 
+/**
+ * Save the shop's currency to the shop_currency shop metafield
+ * The discount function converts store credit from it and leaves out credit in
+ * any currency other than the shop's or the cart's
+ */
+export async function saveShopCurrency(admin: AdminApiContext): Promise<boolean> {
+  const query = `#graphql
+    query GetShopCurrency {
+      shop {
+        id
+        currencyCode
+      }
+    }
+  `;
+
+  const response = await admin.graphql(query);
+  const data = await response.json();
+  const shop = data.data?.shop;
+
+  if (!shop?.id || !shop?.currencyCode) {
+    console.error("Could not read the shop's currency");
+    return false;
+  }
+
+  const mutation = `#graphql
+    mutation SetShopCurrency($metafields: [MetafieldsSetInput!]!) {
+      metafieldsSet(metafields: $metafields) {
+        userErrors {
+          field
+          message
+        }
+      }
+    }
+  `;
+
+  const setResponse = await admin.graphql(mutation, {
+    variables: {
+      metafields: [
+        {
+          ownerId: shop.id,
+          namespace: METAFIELD_NAMESPACE,
+          key: "shop_currency",
+          type: "single_line_text_field",
+          value: shop.currencyCode,
+        },
+      ],
+    },
+  });
+
+  const setData = await setResponse.json();
+  const errors = setData.data?.metafieldsSet?.userErrors || [];
+
+  if (errors.length > 0) {
+    console.error("Shop currency metafield set errors:", errors);
+    return false;
+  }
+
+  return true;
+}
+
 /**
  * Increment customer referral credits
  */
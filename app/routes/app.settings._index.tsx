@@ -6,6 +6,7 @@
  * - Minimum order amount for discount
  * - Referrer credit amount
  * - Minimum orders required to be a referrer
+ * - Store credit redemption rules (on the store credit discount)
  */
 
 import { useState, useEffect } from "react";
@@ -22,7 +23,13 @@ import {
   getOrCreateDaisychainDiscount,
   loadDiscountConfig,
   saveDiscountConfig,
+  loadStoreCreditRules,
+  saveStoreCreditConfig,
+  syncStoreCreditQueryVariables,
+  DEFAULT_STORE_CREDIT_RULES,
   type DiscountConfig,
+  type DiscountMode,
+  type StoreCreditRules,
 } from "../lib/discount-config";
 import {
   getShopConfig,
@@ -36,6 +43,7 @@ export const loader = async ({ request }: LoaderFunctionArgs) => {
   if (!admin || !session?.shop) {
     return {
       config: {
+        mode: "referral" as DiscountMode,
         referee_discount_percentage: 10,
         referee_min_order: 0,
         referrer_credit_amount: 5.0,
@@ -51,6 +59,7 @@ export const loader = async ({ request }: LoaderFunctionArgs) => {
         widget_text_color: "#ffffff",
         email_notifications_enabled: true,
       },
+      storeCreditRules: DEFAULT_STORE_CREDIT_RULES,
       discountId: null,
       shop: session?.shop || "",
       emailFromAddress: process.env.RESEND_FROM_EMAIL || "noreply@resend.dev",
@@ -87,8 +96,13 @@ export const loader = async ({ request }: LoaderFunctionArgs) => {
     config = await loadDiscountConfig(admin, discountId);
   }
 
+  const storeCreditRules = shopConfig.storeCreditDiscountId
+    ? await loadStoreCreditRules(admin, shopConfig.storeCreditDiscountId)
+    : DEFAULT_STORE_CREDIT_RULES;
+
   return {
     config: config || {
+      mode: "referral" as DiscountMode,
       referee_discount_percentage: 10,
       referee_min_order: 0,
       referrer_credit_amount: 5.0,
@@ -104,6 +118,7 @@ export const loader = async ({ request }: LoaderFunctionArgs) => {
         widget_text_color: "#ffffff",
         email_notifications_enabled: true,
     },
+    storeCreditRules,
     discountId,
     shop: session.shop,
     emailFromAddress: process.env.RESEND_FROM_EMAIL || "noreply@resend.dev",
@@ -120,7 +135,9 @@ export const action = async ({ request }: ActionFunctionArgs) => {
   }
 
   const formData = await request.formData();
-  const config: DiscountConfig = {
+  const formValues: DiscountConfig = {
+    // These settings are the referral discount's; store credit has its own discount
+    mode: "referral",
     referee_discount_percentage: parseFloat(
       formData.get("referee_discount_percentage")?.toString() || "10",
     ),
@@ -157,7 +174,7 @@ export const action = async ({ request }: ActionFunctionArgs) => {
   };
   
   // Validate discount percentage
-  if (config.referee_discount_percentage < 0 || config.referee_discount_percentage > 100) {
+  if (formValues.referee_discount_percentage < 0 || formValues.referee_discount_percentage > 100) {
     return { 
       success: false, 
       error: "Discount percentage must be between 0 and 100" 
@@ -165,13 +182,42 @@ export const action = async ({ request }: ActionFunctionArgs) => {
   }
   
   // Validate minimum order
-  if (config.referee_min_order < 0) {
+  if (formValues.referee_min_order < 0) {
     return { 
       success: false, 
       error: "Minimum order amount cannot be negative" 
     };
   }
 
+  // Store credit rules (empty limits mean no limit)
+  const optionalNumber = (key: string) => {
+    const value = formData.get(key)?.toString() ?? "";
+    return value === "" ? undefined : parseFloat(value);
+  };
+  const storeCreditRules: StoreCreditRules = {
+    min_subtotal: optionalNumber("store_credit_min_subtotal") ?? 0,
+    max_amount_per_order: optionalNumber("store_credit_max_amount_per_order"),
+    max_order_percentage: optionalNumber("store_credit_max_order_percentage"),
+  };
+
+  if (
+    !(storeCreditRules.min_subtotal >= 0) ||
+    !((storeCreditRules.max_amount_per_order ?? 0) >= 0)
+  ) {
+    return {
+      success: false,
+      error: "Store credit amounts cannot be negative",
+    };
+  }
+
+  const maxOrderPercentage = storeCreditRules.max_order_percentage ?? 0;
+  if (!(maxOrderPercentage >= 0 && maxOrderPercentage <= 100)) {
+    return {
+      success: false,
+      error: "Store credit order percentage must be between 0 and 100",
+    };
+  }
+
   // Ensure app is set up
   try {
     await ensureAppSetup(admin, session.shop);
@@ -194,7 +240,7 @@ export const action = async ({ request }: ActionFunctionArgs) => {
     console.log("[Settings Action] No discount ID found, creating discount...");
     // Use functionHandle from shopify.extension.toml (more stable than functionId)
     const functionHandle = "daisychain-discount-function";
-    discountId = await getOrCreateDaisychainDiscount(admin, functionId, config, functionHandle);
+    discountId = await getOrCreateDaisychainDiscount(admin, functionId, formValues, functionHandle);
     if (discountId) {
       await storeDiscountId(session.shop, discountId);
       console.log(`[Settings Action] Created and stored discount ID: ${discountId}`);
@@ -219,6 +265,13 @@ export const action = async ({ request }: ActionFunctionArgs) => {
     // Log warnings but continue - we'll try to save anyway
   }
 
+  // Merge into the saved config so settings the form doesn't show (programs,
+  // schedules, messages and so on) are kept
+  const config: DiscountConfig = {
+    ...(await loadDiscountConfig(admin, discountId)),
+    ...formValues,
+  };
+
   const success = await saveDiscountConfig(admin, discountId, config);
 
   if (!success) {
@@ -228,15 +281,35 @@ export const action = async ({ request }: ActionFunctionArgs) => {
     };
   }
 
-  return { success, config };
+  // Store credit rules belong to the store credit discount
+  const storeCreditDiscountId = shopConfig.storeCreditDiscountId;
+  if (!storeCreditDiscountId) {
+    return {
+      success: false,
+      error: "Store credit discount not found, so its rules weren't saved. Reload the page to set it up.",
+    };
+  }
+
+  if (
+    !(await saveStoreCreditConfig(admin, storeCreditDiscountId, storeCreditRules)) ||
+    !(await syncStoreCreditQueryVariables(admin, discountId, storeCreditDiscountId))
+  ) {
+    return {
+      success: false,
+      error: "Failed to save store credit rules. Please check the console for details.",
+    };
+  }
+
+  return { success, config, storeCreditRules };
 };
 
 export default function Settings() {
-  const { config: initialConfig, discountId, emailFromAddress, emailFromName, resendApiKeyConfigured } = useLoaderData<typeof loader>();
+  const { config: initialConfig, storeCreditRules: initialStoreCreditRules, discountId, emailFromAddress, emailFromName, resendApiKeyConfigured } = useLoaderData<typeof loader>();
   const fetcher = useFetcher<typeof action>();
   const shopify = useAppBridge();
 
   const [config, setConfig] = useState(initialConfig);
+  const [storeCreditRules, setStoreCreditRules] = useState<StoreCreditRules>(initialStoreCreditRules);
   const [activeTab, setActiveTab] = useState<"rewards" | "styling" | "emails">("rewards");
 
   // Update local state when loader data changes
@@ -244,6 +317,10 @@ export default function Settings() {
     setConfig(initialConfig);
   }, [initialConfig]);
 
+  useEffect(() => {
+    setStoreCreditRules(initialStoreCreditRules);
+  }, [initialStoreCreditRules]);
+
   // Show toast on save
   useEffect(() => {
     if (fetcher.data?.success) {
@@ -252,6 +329,9 @@ export default function Settings() {
       if (fetcher.data.config) {
         setConfig(fetcher.data.config);
       }
+      if (fetcher.data.storeCreditRules) {
+        setStoreCreditRules(fetcher.data.storeCreditRules);
+      }
     } else if (fetcher.data?.error) {
       shopify.toast.show(`Error: ${fetcher.data.error}`, { isError: true });
     }
@@ -259,7 +339,17 @@ export default function Settings() {
 
   const handleSubmit = (e: React.FormEvent<HTMLFormElement>) => {
     e.preventDefault();
-    const formData = new FormData(e.currentTarget);
+    // Submit every setting, not just the fields on the open tab
+    // (checkboxes are sent as "true" / "false")
+    const formData = new FormData();
+    for (const [key, value] of Object.entries(config)) {
+      if (value !== null && typeof value !== "object") {
+        formData.set(key, String(value));
+      }
+    }
+    for (const [key, value] of Object.entries(storeCreditRules)) {
+      formData.set(`store_credit_${key}`, value === undefined ? "" : String(value));
+    }
     fetcher.submit(formData, { method: "POST" });
   };
 
@@ -704,6 +794,114 @@ export default function Settings() {
                     </s-stack>
                   </s-stack>
                 </s-box>
+
+                <s-box padding="base" borderWidth="base" borderRadius="base">
+                  <s-stack direction="block" gap="base">
+                    <s-heading>Store Credit</s-heading>
+                    <div style={{ marginBottom: "4px" }}>
+                      <s-text tone="neutral">
+                        Rules for redeeming store credit at checkout (applied by the Daisychain Store Credits discount)
+                      </s-text>
+                    </div>
+                    <s-stack direction="block" gap="small">
+                      <label>
+                        <s-text>
+                          <strong>Minimum Order Amount</strong>
+                        </s-text>
+                        <div style={{ marginTop: "4px", marginBottom: "8px" }}>
+                          <s-text tone="neutral">
+                            Order total (excluding gift cards and excluded products) required to use store credit (0 = no minimum)
+                          </s-text>
+                        </div>
+                        <input
+                          type="number"
+                          name="store_credit_min_subtotal"
+                          value={storeCreditRules.min_subtotal}
+                          onChange={(e) =>
+                            setStoreCreditRules({
+                              ...storeCreditRules,
+                              min_subtotal: parseFloat(e.target.value) || 0,
+                            })
+                          }
+                          min="0"
+                          step="0.01"
+                          style={{
+                            width: "100%",
+                            padding: "8px",
+                            marginTop: "4px",
+                            borderRadius: "4px",
+                            border: "1px solid #ccc",
+                          }}
+                        />
+                      </label>
+
+                      <label>
+                        <s-text>
+                          <strong>Maximum Credit per Order</strong>
+                        </s-text>
+                        <div style={{ marginTop: "4px", marginBottom: "8px" }}>
+                          <s-text tone="neutral">
+                            Most store credit a customer can use on one order (leave empty for no limit)
+                          </s-text>
+                        </div>
+                        <input
+                          type="number"
+                          name="store_credit_max_amount_per_order"
+                          value={storeCreditRules.max_amount_per_order ?? ""}
+                          onChange={(e) =>
+                            setStoreCreditRules({
+                              ...storeCreditRules,
+                              max_amount_per_order:
+                                e.target.value === "" ? undefined : parseFloat(e.target.value) || 0,
+                            })
+                          }
+                          min="0"
+                          step="0.01"
+                          style={{
+                            width: "100%",
+                            padding: "8px",
+                            marginTop: "4px",
+                            borderRadius: "4px",
+                            border: "1px solid #ccc",
+                          }}
+                        />
+                      </label>
+
+                      <label>
+                        <s-text>
+                          <strong>Maximum Share of the Order</strong>
+                        </s-text>
+                        <div style={{ marginTop: "4px", marginBottom: "8px" }}>
+                          <s-text tone="neutral">
+                            Percentage of the order store credit can pay for (leave empty for no limit)
+                          </s-text>
+                        </div>
+                        <input
+                          type="number"
+                          name="store_credit_max_order_percentage"
+                          value={storeCreditRules.max_order_percentage ?? ""}
+                          onChange={(e) =>
+                            setStoreCreditRules({
+                              ...storeCreditRules,
+                              max_order_percentage:
+                                e.target.value === "" ? undefined : parseFloat(e.target.value) || 0,
+                            })
+                          }
+                          min="0"
+                          max="100"
+                          step="0.1"
+                          style={{
+                            width: "100%",
+                            padding: "8px",
+                            marginTop: "4px",
+                            borderRadius: "4px",
+                            border: "1px solid #ccc",
+                          }}
+                        />
+                      </label>
+                    </s-stack>
+                  </s-stack>
+                </s-box>
               </>
             )}
 
@@ -11,6 +11,8 @@
 import type { HeadersFunction, LoaderFunctionArgs } from "react-router";
 import { authenticate } from "../shopify.server";
 import { findCustomerByName, findCustomersByName, anonymizeEmail } from "../lib/shopify-queries";
+import { getShopConfig } from "../lib/function-management";
+import { getReferralTokenSigning, issueReferralToken } from "../lib/referral-token";
 
 /**
  * Set headers for app proxy responses
@@ -170,16 +172,27 @@ export async function loader({ request }: LoaderFunctionArgs) {
       );
     }
 
+    // Signed tokens for the referral_token cart attribute, checked by the discount function
+    // (the key is resolved once, so the first lookup only creates one)
+    const { discountId } = await getShopConfig(context.session?.shop || shop);
+    const signing = await getReferralTokenSigning(admin, discountId);
+    const referralTokens = matchingCustomers.map((customer) =>
+      signing ? issueReferralToken(signing, customer) : null,
+    );
+
     // If multiple customers found, return duplicates with anonymized emails
     if (matchingCustomers.length > 1) {
       return Response.json(
         {
           success: false,
           duplicates: true,
-          customers: matchingCustomers.map(customer => ({
+          customers: matchingCustomers.map((customer, index) => ({
             id: customer.id,
             displayName: customer.displayName,
             anonymizedEmail: anonymizeEmail(customer.email),
+            numberOfOrders: customer.numberOfOrders,
+            referralProgram: customer.referralProgram,
+            referralToken: referralTokens[index],
           })),
         },
         {
@@ -199,6 +212,9 @@ export async function loader({ request }: LoaderFunctionArgs) {
           id: customer.id,
           displayName: customer.displayName,
           email: customer.email,
+          numberOfOrders: customer.numberOfOrders,
+          referralProgram: customer.referralProgram,
+          referralToken: referralTokens[0],
         },
       },
       {
@@ -9,6 +9,9 @@
 
 import type { ActionFunctionArgs, HeadersFunction } from "react-router";
 import { authenticate, apiVersion } from "../shopify.server";
+import { getShopConfig } from "../lib/function-management";
+import { getCustomerById } from "../lib/shopify-queries";
+import { getReferralTokenSigning, issueReferralToken } from "../lib/referral-token";
 
 /**
  * Set headers for app proxy responses
@@ -80,7 +83,7 @@ export async function action({ request }: ActionFunctionArgs) {
 
   try {
     // Authenticate app proxy request to verify it's from Shopify
-    const { session } = await authenticate.public.appProxy(request);
+    const { session, admin } = await authenticate.public.appProxy(request);
     
     if (!session) {
       return Response.json(
@@ -147,6 +150,25 @@ export async function action({ request }: ActionFunctionArgs) {
       { key: "referral_validated", value: "true" },
     ];
 
+    // Sign the referrer, their order count, email hash and program so the discount function can
+    // tell this referral came from us (min_referrer_orders is checked against the signed count)
+    if (admin) {
+      const referrer = await getCustomerById(admin, referrerId);
+      const { discountId } = await getShopConfig(shopDomain);
+      const signing = referrer ? await getReferralTokenSigning(admin, discountId) : null;
+      const referralToken =
+        referrer && signing
+          ? issueReferralToken(signing, referrer)
+          : null;
+      if (referralToken) {
+        newAttributes.push({ key: "referral_token", value: referralToken });
+      }
+      // The referrer's program selects the config's named program (must match the token)
+      if (referrer) {
+        newAttributes.push({ key: "referral_program", value: referrer.referralProgram });
+      }
+    }
+
     // Update cart attributes
     // Note: cartAttributesUpdate is a Storefront API mutation (not Admin API)
     // IDE may show error "Cannot query field cartAttributesUpdate on type Mutation"
@@ -1,3 +1,10 @@
+use crate::cart_lines_discounts_generate_run::{
+    DiscountConfig, DiscountMode, ShippingDiscountScope,
+};
+use crate::currency::to_presentment;
+use crate::messages::{localized_template, render, MessageKind};
+use crate::money::Money;
+use crate::referee::{check_referee, running_program, RefereeRejection, Referral};
 use crate::schema;
 use shopify_function::prelude::*;
 use shopify_function::Result;
@@ -15,26 +22,197 @@ fn cart_delivery_options_discounts_generate_run(
         return Ok(schema::CartDeliveryOptionsDiscountsGenerateRunResult { operations: vec![] });
     }
 
-    let first_delivery_group = input
+    // Get discount configuration from metafield
+    let config: &DiscountConfig = match input.discount().metafield() {
+        Some(metafield) => metafield.json_value(),
+        None => {
+            // No metafield configured, shipping discount stays off
+            return Ok(schema::CartDeliveryOptionsDiscountsGenerateRunResult {
+                operations: vec![],
+            });
+        }
+    };
+
+    // The referral discount's shipping perk also covers credit holders, as it
+    // always has; a store credit discount only covers credit holders
+    let referees_qualify = match &config.mode {
+        None => {
+            log!("Config has no mode, treating it as the referral discount (legacy config)");
+            true
+        }
+        Some(DiscountMode::Referral) | Some(DiscountMode::Combined) => true,
+        Some(DiscountMode::StoreCredit) => false,
+        Some(DiscountMode::Unknown(mode)) => {
+            log!("Unknown discount mode {:?}, no discount applied", mode);
+            return Ok(schema::CartDeliveryOptionsDiscountsGenerateRunResult {
+                operations: vec![],
+            });
+        }
+    };
+
+    let local_time = input.shop().local_time();
+
+    let config = match running_program(
+        config,
+        input
+            .cart()
+            .referral_program()
+            .and_then(|attr| attr.value())
+            .map(|v| v.as_str()),
+        local_time.date(),
+        input
+            .discount()
+            .query_variables()
+            .map(|metafield| metafield.json_value()),
+        || *local_time.in_active_hours(),
+    ) {
+        Some(config) => config,
+        None => {
+            return Ok(schema::CartDeliveryOptionsDiscountsGenerateRunResult { operations: vec![] })
+        }
+    };
+
+    // A 0% shipping discount means the merchant hasn't enabled it
+    if config.shipping_discount_percentage <= 0.0 {
+        return Ok(schema::CartDeliveryOptionsDiscountsGenerateRunResult { operations: vec![] });
+    }
+
+    // Only referees or customers holding store credit qualify
+    let is_referee = referees_qualify && {
+        match check_referee(config, &Referral::from(&input)) {
+            Ok(()) => true,
+            Err(RefereeRejection::NoReferral) => false,
+            Err(rejection) => {
+                log!("Referral shipping discount rejected: {}", rejection);
+                false
+            }
+        }
+    };
+
+    let currency_code = input.cart().cost().subtotal_amount().currency_code();
+    let presentment_currency_rate = input.presentment_currency_rate().as_f64();
+
+    let customer = input
         .cart()
-        .delivery_groups()
-        .first()
-        .ok_or("No delivery groups found")?;
+        .buyer_identity()
+        .and_then(|identity| identity.customer());
+
+    let available_credits = match customer.and_then(|customer| customer.metafield()) {
+        Some(m) => match m.json_value().balance() {
+            Ok(balance) => balance.available(
+                local_time.date(),
+                input
+                    .shop()
+                    .shop_currency()
+                    .map(|metafield| metafield.value().as_str()),
+                presentment_currency_rate,
+                currency_code,
+            ),
+            Err(error) => {
+                log!(
+                    "Store credit ignored: invalid referral_credits metafield: {}",
+                    error
+                );
+                Money::zero(currency_code)
+            }
+        },
+        None => Money::zero(currency_code),
+    };
+
+    let is_credit_holder = available_credits.is_positive();
+
+    if !is_referee && !is_credit_holder {
+        return Ok(schema::CartDeliveryOptionsDiscountsGenerateRunResult { operations: vec![] });
+    }
+
+    // Check if cart meets minimum order requirement for the shipping discount
+    let cart_subtotal = Money::from_decimal(
+        input.cart().cost().subtotal_amount().amount().as_f64(),
+        currency_code,
+    );
+
+    // Config amounts are in the shop's currency
+    let shipping_min_order = to_presentment(
+        config.shipping_min_order,
+        presentment_currency_rate,
+        currency_code,
+    );
+
+    if cart_subtotal < shipping_min_order {
+        return Ok(schema::CartDeliveryOptionsDiscountsGenerateRunResult { operations: vec![] });
+    }
+
+    let delivery_groups = input.cart().delivery_groups();
+
+    let targets: Vec<schema::DeliveryDiscountCandidateTarget> = match config.shipping_discount_scope
+    {
+        ShippingDiscountScope::AllGroups => delivery_groups
+            .iter()
+            .map(|group| {
+                schema::DeliveryDiscountCandidateTarget::DeliveryGroup(
+                    schema::DeliveryGroupTarget {
+                        id: group.id().clone(),
+                    },
+                )
+            })
+            .collect(),
+        ShippingDiscountScope::FirstGroup => delivery_groups
+            .first()
+            .map(|group| {
+                schema::DeliveryDiscountCandidateTarget::DeliveryGroup(
+                    schema::DeliveryGroupTarget {
+                        id: group.id().clone(),
+                    },
+                )
+            })
+            .into_iter()
+            .collect(),
+        ShippingDiscountScope::SelectedOptions => delivery_groups
+            .iter()
+            .filter_map(|group| group.selected_delivery_option())
+            .map(|option| {
+                schema::DeliveryDiscountCandidateTarget::DeliveryOption(
+                    schema::DeliveryOptionTarget {
+                        handle: option.handle().clone(),
+                    },
+                )
+            })
+            .collect(),
+    };
+
+    // No delivery groups (e.g. digital-only cart), nothing to discount
+    if targets.is_empty() {
+        return Ok(schema::CartDeliveryOptionsDiscountsGenerateRunResult { operations: vec![] });
+    }
+
+    let shipping_percentage = config.shipping_discount_percentage.min(100.0);
+
+    let language = input.localization().language().iso_code();
+
+    let message = match localized_template(&config.messages, language, MessageKind::Shipping) {
+        Some(template) => render(
+            template,
+            &[("percentage", &shipping_percentage.to_string())],
+        ),
+        None => config.shipping_discount_message.clone().unwrap_or_else(|| {
+            if shipping_percentage >= 100.0 {
+                "FREE DELIVERY".to_string()
+            } else {
+                format!("{}% OFF DELIVERY", shipping_percentage)
+            }
+        }),
+    };
 
     Ok(schema::CartDeliveryOptionsDiscountsGenerateRunResult {
         operations: vec![schema::DeliveryOperation::DeliveryDiscountsAdd(
             schema::DeliveryDiscountsAddOperation {
                 selection_strategy: schema::DeliveryDiscountSelectionStrategy::All,
                 candidates: vec![schema::DeliveryDiscountCandidate {
-                    targets: vec![schema::DeliveryDiscountCandidateTarget::DeliveryGroup(
-                        schema::DeliveryGroupTarget {
-                            id: first_delivery_group.id().clone(),
-                        },
-                    )],
+                    targets,
                     value: schema::DeliveryDiscountCandidateValue::Percentage(schema::Percentage {
-                        value: Decimal::from(100.0),
+                        value: Decimal::from(shipping_percentage),
                     }),
-                    message: Some("FREE DELIVERY".to_string()),
+                    message: Some(message),
                     associated_discount_code: None,
                 }],
             },
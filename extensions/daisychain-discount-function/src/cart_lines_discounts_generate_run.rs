@@ -1,168 +1,1307 @@
+use crate::currency::{format_money, to_presentment};
+use crate::dates::{add_days, is_iso_date, weekday};
+use crate::messages::{localized_template, render, MessageKind, MessageTemplates};
+use crate::money::Money;
+use crate::referee::{check_referee, running_program, RefereeRejection, Referral};
 use crate::schema;
 use shopify_function::prelude::*;
 use shopify_function::Result;
+use std::collections::HashMap;
 
 #[derive(Deserialize, Default, PartialEq)]
 pub struct DiscountConfig {
+    // Which discount this instance gives (None = referral, logged, for configs
+    // saved before the mode existed). Ignored inside `programs`.
+    #[shopify_function(default)]
+    pub mode: Option<DiscountMode>,
+    // Store credit configs don't need the referral settings
+    #[shopify_function(default)]
     pub referee_discount_percentage: f64,
+    #[shopify_function(default)]
     pub referee_min_order: f64,
+    #[shopify_function(default)]
     pub referrer_credit_amount: f64,
+    #[shopify_function(default)]
     pub min_referrer_orders: i32,
+    // Shipping discount settings (0% = shipping discount disabled)
+    #[shopify_function(default)]
+    pub shipping_discount_percentage: f64,
+    #[shopify_function(default)]
+    pub shipping_min_order: f64,
+    #[shopify_function(default)]
+    pub shipping_discount_message: Option<String>,
+    #[shopify_function(default)]
+    pub shipping_discount_scope: ShippingDiscountScope,
+    // Tiered referee discounts (empty = use the flat percentage/min order above)
+    #[shopify_function(default)]
+    pub referee_discount_tiers: Vec<DiscountTier>,
+    // Fixed-amount referee discounts ("$10 off your first order")
+    #[shopify_function(default)]
+    pub referee_discount_type: RefereeDiscountType,
+    #[shopify_function(default)]
+    pub referee_discount_amount: f64,
+    // Per-order cap for percentage referee discounts (None = uncapped)
+    #[shopify_function(default)]
+    pub referee_max_discount_amount: Option<f64>,
+    // Line-level referee discounts (requires the PRODUCT discount class)
+    #[shopify_function(default)]
+    pub referee_discount_target: RefereeDiscountTarget,
+    // Only discount products tagged `daisychain-eligible` or in the
+    // `eligibleCollections` query variable (product target only)
+    #[shopify_function(default)]
+    pub referee_require_eligible_tag: bool,
+    // Product types never discounted (gift cards and `daisychain-excluded` products never are)
+    #[shopify_function(default)]
+    pub excluded_product_types: Vec<String>,
+    // Also give the referee discount to customers who have ordered before
+    #[shopify_function(default)]
+    pub referee_allow_returning_customers: bool,
+    // Days after the referral before the referee discount applies (0 = immediately)
+    #[shopify_function(default)]
+    pub referee_available_after_days: i32,
+    // Check the minimum order in the function instead of with discount
+    // conditions (product-target discounts are always checked in the function)
+    #[shopify_function(default)]
+    pub referee_min_order_in_function: bool,
+    // Apply the referee discount before the referral is validated
+    #[shopify_function(default)]
+    pub referee_redeemable_before_referral: bool,
+    // Campaign schedule in the shop's time zone: ISO dates, inclusive (None = open-ended)
+    #[shopify_function(default)]
+    pub starts_at: Option<String>,
+    #[shopify_function(default)]
+    pub ends_at: Option<String>,
+    // Days of the week the campaign runs ("mon" to "sun", empty = every day)
+    #[shopify_function(default)]
+    pub active_weekdays: Vec<String>,
+    // Hours the campaign runs, from the start hour up to the end hour (0-24,
+    // wrapping past midnight when the end is before the start). Top-level
+    // only: the input query checks a single window, so they apply to every
+    // program and the app writes them to the query variables. Equal hours, or
+    // hours the query variables weren't updated for, are logged and ignored
+    #[shopify_function(default)]
+    pub active_start_hour: Option<i32>,
+    #[shopify_function(default)]
+    pub active_end_hour: Option<i32>,
+    // Checkout message templates keyed by language code ("fr", "pt-BR")
+    #[shopify_function(default)]
+    pub messages: HashMap<String, MessageTemplates>,
+    // Named programs ("influencer", "employee") selected by the `referral_program`
+    // cart attribute, each with its own complete settings
+    #[shopify_function(default)]
+    pub programs: HashMap<String, DiscountConfig>,
+}
+
+/// The `query_variables` metafield, as far as the function checks it.
+#[derive(Deserialize, Default, PartialEq)]
+#[shopify_function(rename_all = "camelCase")]
+pub struct QueryVariables {
+    // The `timeBetween` window the input query checked (the query's defaults
+    // when unset)
+    #[shopify_function(default)]
+    pub active_start_time: Option<String>,
+    #[shopify_function(default)]
+    pub active_end_time: Option<String>,
+}
+
+/// Which discount a discount instance gives.
+#[derive(PartialEq, Clone, Debug)]
+pub enum DiscountMode {
+    /// The referee discount
+    Referral,
+    /// The customer's store credit
+    StoreCredit,
+    /// The referee discount and store credit together
+    Combined,
+    /// A mode this function version doesn't know; no discount is applied
+    Unknown(String),
+}
+
+impl shopify_function::wasm_api::Deserialize for DiscountMode {
+    fn deserialize(
+        value: &shopify_function::wasm_api::Value,
+    ) -> std::result::Result<Self, shopify_function::wasm_api::read::Error> {
+        // Unknown modes are kept rather than failing the whole config
+        let mode = value.as_string().unwrap_or_default();
+        match mode.as_str() {
+            "referral" => Ok(Self::Referral),
+            "store_credit" => Ok(Self::StoreCredit),
+            "combined" => Ok(Self::Combined),
+            _ => Ok(Self::Unknown(mode)),
+        }
+    }
+}
+
+/// Store credit rules from the `store_credit_config` metafield on the
+/// store-credit discount. Without the metafield, any order can redeem credit
+/// up to its whole eligible subtotal.
+#[derive(Deserialize, Default, PartialEq)]
+pub struct StoreCreditConfig {
+    // Eligible subtotal required before credit can be redeemed
+    #[shopify_function(default)]
+    pub min_subtotal: f64,
+    // Most credit redeemable on one order (None = no limit)
+    #[shopify_function(default)]
+    pub max_amount_per_order: Option<f64>,
+    // Share of the eligible subtotal credit may cover, 0-100 (None = no limit)
+    #[shopify_function(default)]
+    pub max_order_percentage: Option<f64>,
+}
+
+impl StoreCreditConfig {
+    /// Returns the most credit that may be applied to an order with this
+    /// eligible subtotal, or `None` when the order is below `min_subtotal`.
+    ///
+    /// Config amounts are in the shop's currency; the limit is in the
+    /// presentment currency.
+    pub fn credit_limit(
+        &self,
+        eligible_subtotal: Money,
+        presentment_currency_rate: f64,
+        currency_code: &str,
+    ) -> Option<Money> {
+        if eligible_subtotal
+            < to_presentment(self.min_subtotal, presentment_currency_rate, currency_code)
+        {
+            return None;
+        }
+
+        let percentage_limit = match self.max_order_percentage {
+            Some(percentage) => eligible_subtotal.percentage(percentage.clamp(0.0, 100.0)),
+            None => eligible_subtotal,
+        };
+
+        Some(match self.max_amount_per_order {
+            Some(amount) => percentage_limit.min(to_presentment(
+                amount,
+                presentment_currency_rate,
+                currency_code,
+            )),
+            None => percentage_limit,
+        })
+    }
+}
+
+/// The customer's `used_referral` metafield, set by the orders webhook once
+/// they've placed an order with a referral.
+#[derive(Deserialize, Default, PartialEq)]
+pub struct UsedReferral {
+    #[shopify_function(default)]
+    pub used: bool,
+}
+
+/// A referee discount unlocked at a cart subtotal threshold.
+///
+/// `percentage` is used for percentage discounts and `amount` for fixed-amount
+/// discounts, following `referee_discount_type`.
+#[derive(Deserialize, Default, PartialEq, Clone, Copy)]
+pub struct DiscountTier {
+    pub min_subtotal: f64,
+    #[shopify_function(default)]
+    pub percentage: f64,
+    #[shopify_function(default)]
+    pub amount: f64,
+}
+
+/// The query variable time for an hour, as the app writes it (`24` is the
+/// end of the day).
+fn hour_time(hour: i32) -> String {
+    if hour >= 24 {
+        "23:59:59".to_string()
+    } else {
+        format!("{:02}:00:00", hour.max(0))
+    }
+}
+
+/// How the referee discount value is expressed.
+#[derive(Default, PartialEq, Clone, Copy)]
+pub enum RefereeDiscountType {
+    #[default]
+    Percentage,
+    FixedAmount,
+    /// A type this function version doesn't know; the referral is skipped
+    Unknown,
+}
+
+impl shopify_function::wasm_api::Deserialize for RefereeDiscountType {
+    fn deserialize(
+        value: &shopify_function::wasm_api::Value,
+    ) -> std::result::Result<Self, shopify_function::wasm_api::read::Error> {
+        let discount_type = value.as_string().unwrap_or_default();
+        match discount_type.as_str() {
+            "percentage" => Ok(Self::Percentage),
+            "fixed_amount" => Ok(Self::FixedAmount),
+            _ => {
+                log!(
+                    "Unknown referee_discount_type {:?}, referral discount skipped",
+                    discount_type
+                );
+                Ok(Self::Unknown)
+            }
+        }
+    }
+}
+
+/// What the referee discount is applied to.
+#[derive(Default, PartialEq, Clone, Copy)]
+pub enum RefereeDiscountTarget {
+    /// The order subtotal (ORDER discount class)
+    #[default]
+    Order,
+    /// Eligible cart lines (PRODUCT discount class)
+    Products,
+    /// A target this function version doesn't know; the referral is skipped
+    Unknown,
+}
+
+impl shopify_function::wasm_api::Deserialize for RefereeDiscountTarget {
+    fn deserialize(
+        value: &shopify_function::wasm_api::Value,
+    ) -> std::result::Result<Self, shopify_function::wasm_api::read::Error> {
+        let target = value.as_string().unwrap_or_default();
+        match target.as_str() {
+            "order" => Ok(Self::Order),
+            "products" => Ok(Self::Products),
+            _ => {
+                log!(
+                    "Unknown referee_discount_target {:?}, referral discount skipped",
+                    target
+                );
+                Ok(Self::Unknown)
+            }
+        }
+    }
+}
+
+/// A referee discount tier converted to the presentment currency.
+#[derive(Clone, Copy)]
+pub struct RefereeTier {
+    pub min_subtotal: Money,
+    // Discount conditions take the minimum in the shop's currency
+    pub shop_min_subtotal: f64,
+    pub percentage: f64,
+    pub amount: Money,
+}
+
+/// A referee discount value before it's mapped onto an order or product candidate.
+enum RefereeDiscountValue {
+    Percentage(f64),
+    FixedAmount(Money),
+}
+
+impl DiscountConfig {
+    /// Whether the referrer has placed enough orders for their link to count.
+    ///
+    /// The order count comes from the `referrer_order_count` cart attribute set
+    /// by the app proxy. When `min_referrer_orders` is positive, a missing or
+    /// unreadable count is treated as not qualifying.
+    pub fn referrer_qualifies(&self, referrer_order_count: Option<&str>) -> bool {
+        if self.min_referrer_orders <= 0 {
+            return true;
+        }
+
+        referrer_order_count
+            .and_then(|count| count.trim().parse::<i32>().ok())
+            .is_some_and(|count| count >= self.min_referrer_orders)
+    }
+
+    /// Whether a buyer may receive the referee discount given their history.
+    ///
+    /// Referee discounts are for new customers: buyers with previous orders or
+    /// an earlier referee redemption only qualify when
+    /// `referee_allow_returning_customers` is set. Guest buyers count as new.
+    pub fn allows_referee(&self, number_of_orders: i32, has_redeemed_referral: bool) -> bool {
+        self.referee_allow_returning_customers || (number_of_orders == 0 && !has_redeemed_referral)
+    }
+
+    /// Whether the referee discount is available yet on `today` (the shop's
+    /// local date).
+    ///
+    /// The wait starts on `referred_on`, the shop's local date when the
+    /// referral was entered: signed into `referral_token` by the app proxy,
+    /// or the `referred_on` cart attribute for discounts without a signing
+    /// key. When `referee_available_after_days` is positive, a missing or
+    /// unreadable date is treated as not available.
+    pub fn referee_available(&self, referred_on: Option<&str>, today: &str) -> bool {
+        if self.referee_available_after_days <= 0 {
+            return true;
+        }
+
+        referred_on
+            .and_then(|date| add_days(date.trim(), i64::from(self.referee_available_after_days)))
+            .is_some_and(|available_on| today >= available_on.as_str())
+    }
+
+    /// Returns the settings for the program named in the `referral_program`
+    /// cart attribute.
+    ///
+    /// A selected program replaces the top-level settings entirely. Without
+    /// the attribute, or when it names no configured program, the top-level
+    /// settings apply.
+    pub fn program(&self, referral_program: Option<&str>) -> &DiscountConfig {
+        let Some(name) = referral_program
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        else {
+            return self;
+        };
+
+        match self.programs.get(name) {
+            Some(program) => {
+                if program.active_start_hour.is_some() || program.active_end_hour.is_some() {
+                    log!(
+                        "Referral program {:?} sets active hours, using the top-level hours instead",
+                        name
+                    );
+                }
+                program
+            }
+            None => {
+                log!(
+                    "Unknown referral program {:?}, using the default settings",
+                    name
+                );
+                self
+            }
+        }
+    }
+
+    /// Whether the campaign is running on `today` (the shop's local date).
+    ///
+    /// A malformed `starts_at` or `ends_at` keeps the campaign off. Hours are
+    /// checked separately by [`Self::in_active_hours`].
+    pub fn is_running(&self, today: &str) -> bool {
+        // ISO dates compare correctly as strings
+        let started = self
+            .starts_at
+            .as_deref()
+            .is_none_or(|starts_at| is_iso_date(starts_at) && today >= starts_at);
+        let ended = self
+            .ends_at
+            .as_deref()
+            .is_some_and(|ends_at| !is_iso_date(ends_at) || today > ends_at);
+
+        if !started || ended {
+            return false;
+        }
+
+        if self.active_weekdays.is_empty() {
+            return true;
+        }
+
+        let Some(today) = weekday(today) else {
+            return false;
+        };
+
+        // Accept "mon", "Monday" and so on
+        self.active_weekdays.iter().any(|day| {
+            day.trim()
+                .get(..3)
+                .is_some_and(|day| day.eq_ignore_ascii_case(today))
+        })
+    }
+
+    /// Whether the shop's local time is within the campaign's active hours.
+    ///
+    /// `in_time_window` is the input query's `timeBetween` check against the
+    /// `activeStartTime` and `activeEndTime` query variables, the earlier and
+    /// later of the two hours. It's only called when an hour window is
+    /// configured; windows that wrap past midnight run outside those bounds.
+    ///
+    /// A window that can't be checked (equal hours, or `query_variables`
+    /// written for other hours) is logged and the campaign runs all day.
+    pub fn in_active_hours(
+        &self,
+        query_variables: Option<&QueryVariables>,
+        in_time_window: impl FnOnce() -> bool,
+    ) -> bool {
+        if self.active_start_hour.is_none() && self.active_end_hour.is_none() {
+            return true;
+        }
+
+        let start = self.active_start_hour.unwrap_or(0);
+        let end = self.active_end_hour.unwrap_or(24);
+
+        if start == end {
+            log!(
+                "active_start_hour and active_end_hour are both {}, hours ignored",
+                start
+            );
+            return true;
+        }
+
+        // The window the input query checked, with the query's defaults
+        let queried = (
+            query_variables
+                .and_then(|variables| variables.active_start_time.as_deref())
+                .unwrap_or("00:00:00"),
+            query_variables
+                .and_then(|variables| variables.active_end_time.as_deref())
+                .unwrap_or("23:59:59"),
+        );
+        let expected = (hour_time(start.min(end)), hour_time(start.max(end)));
+
+        if queried != (expected.0.as_str(), expected.1.as_str()) {
+            log!(
+                "Active hours {}-{} don't match the query_variables window {}-{}, hours ignored (save the settings in the app to update it)",
+                start,
+                end,
+                queried.0,
+                queried.1
+            );
+            return true;
+        }
+
+        if start < end {
+            in_time_window()
+        } else {
+            !in_time_window()
+        }
+    }
+
+    /// Whether a referral may be redeemed given the `referral_validated` cart
+    /// attribute.
+    pub fn accepts_referral(&self, referral_validated: bool) -> bool {
+        referral_validated || self.referee_redeemable_before_referral
+    }
+
+    /// Returns the referee discount tier the cart subtotal qualifies for.
+    ///
+    /// When no tiers are configured, the flat `referee_discount_percentage` and
+    /// `referee_min_order` act as a single tier. With tiers, the highest
+    /// qualifying threshold wins. Config amounts are in the shop's currency;
+    /// the returned tier is converted to the presentment currency.
+    pub fn referee_tier_for(
+        &self,
+        cart_subtotal: Money,
+        presentment_currency_rate: f64,
+        currency_code: &str,
+    ) -> Option<RefereeTier> {
+        let tier = if self.referee_discount_tiers.is_empty() {
+            self.flat_tier()
+        } else {
+            self.referee_discount_tiers
+                .iter()
+                .filter(|tier| {
+                    cart_subtotal
+                        >= to_presentment(
+                            tier.min_subtotal,
+                            presentment_currency_rate,
+                            currency_code,
+                        )
+                })
+                .max_by(|a, b| a.min_subtotal.total_cmp(&b.min_subtotal))
+                .copied()?
+        };
+
+        let tier = RefereeTier::new(tier, presentment_currency_rate, currency_code);
+
+        if cart_subtotal < tier.min_subtotal {
+            return None;
+        }
+
+        Some(tier)
+    }
+
+    /// Returns the lowest referee discount tier, offered to carts that don't
+    /// reach any minimum yet.
+    pub fn referee_entry_tier(
+        &self,
+        presentment_currency_rate: f64,
+        currency_code: &str,
+    ) -> RefereeTier {
+        let tier = self
+            .referee_discount_tiers
+            .iter()
+            .min_by(|a, b| a.min_subtotal.total_cmp(&b.min_subtotal))
+            .copied()
+            .unwrap_or_else(|| self.flat_tier());
+
+        RefereeTier::new(tier, presentment_currency_rate, currency_code)
+    }
+
+    /// The flat `referee_discount_percentage` / `referee_min_order` settings as a tier.
+    fn flat_tier(&self) -> DiscountTier {
+        DiscountTier {
+            min_subtotal: self.referee_min_order,
+            percentage: self.referee_discount_percentage,
+            amount: self.referee_discount_amount,
+        }
+    }
+}
+
+impl RefereeTier {
+    fn new(tier: DiscountTier, presentment_currency_rate: f64, currency_code: &str) -> Self {
+        Self {
+            min_subtotal: to_presentment(
+                tier.min_subtotal,
+                presentment_currency_rate,
+                currency_code,
+            ),
+            shop_min_subtotal: tier.min_subtotal,
+            percentage: tier.percentage,
+            amount: to_presentment(tier.amount, presentment_currency_rate, currency_code),
+        }
+    }
+}
+
+/// Which delivery targets the shipping discount is applied to.
+#[derive(Default, PartialEq, Clone, Copy)]
+pub enum ShippingDiscountScope {
+    /// One target per delivery group (split shipments, multiple locations)
+    #[default]
+    AllGroups,
+    /// Only the first delivery group
+    FirstGroup,
+    /// The buyer's selected delivery option in each delivery group
+    SelectedOptions,
+}
+
+impl shopify_function::wasm_api::Deserialize for ShippingDiscountScope {
+    fn deserialize(
+        value: &shopify_function::wasm_api::Value,
+    ) -> std::result::Result<Self, shopify_function::wasm_api::read::Error> {
+        let scope = value.as_string().unwrap_or_default();
+        match scope.as_str() {
+            "all_groups" => Ok(Self::AllGroups),
+            "first_group" => Ok(Self::FirstGroup),
+            "selected_options" => Ok(Self::SelectedOptions),
+            _ => {
+                log!(
+                    "Unknown shipping_discount_scope {:?}, using all_groups",
+                    scope
+                );
+                Ok(Self::AllGroups)
+            }
+        }
+    }
 }
 
 #[shopify_function]
 fn cart_lines_discounts_generate_run(
     input: schema::cart_lines_discounts_generate_run::Input,
 ) -> Result<schema::CartLinesDiscountsGenerateRunResult> {
-    // Check if discount has ORDER class
+    // Check if discount has ORDER or PRODUCT class
     let has_order_discount_class = input
         .discount()
         .discount_classes()
         .contains(&schema::DiscountClass::Order);
 
-    if !has_order_discount_class {
+    let has_product_discount_class = input
+        .discount()
+        .discount_classes()
+        .contains(&schema::DiscountClass::Product);
+
+    if !has_order_discount_class && !has_product_discount_class {
         return Ok(schema::CartLinesDiscountsGenerateRunResult { operations: vec![] });
     }
 
     // Get cart subtotal (needed for both discount types)
-    let cart_subtotal = input
-        .cart()
-        .cost()
-        .subtotal_amount()
-        .amount()
-        .as_f64();
-
-    // Determine discount type by checking if discount has config metafield:
-    // - Referral discount: has config metafield (contains discount configuration)
-    // - Store credit discount: no config metafield (relies on customer credits)
-    let has_config_metafield = input.discount().metafield().is_some();
-
-    if has_config_metafield {
-        // REFERRAL DISCOUNT LOGIC
-        // Check if referral is validated
-        let referral_validated = input
+    let currency_code = input.cart().cost().subtotal_amount().currency_code();
+    let cart_subtotal = Money::from_decimal(
+        input.cart().cost().subtotal_amount().amount().as_f64(),
+        currency_code,
+    );
+
+    // Config amounts and credit balances are in the shop's currency
+    let presentment_currency_rate = input.presentment_currency_rate().as_f64();
+
+    // Buyer's checkout language for candidate messages
+    let language = input.localization().language().iso_code();
+
+    let cart = CartContext {
+        input: &input,
+        currency_code,
+        cart_subtotal,
+        presentment_currency_rate,
+        language,
+        has_order_discount_class,
+        has_product_discount_class,
+    };
+
+    let operations = match input.discount().metafield() {
+        // Store credit discounts created before they got a config
+        None => {
+            log!("No config metafield, applying store credit (legacy store credit discount)");
+            store_credit(&cart, None, Money::zero(currency_code))
+                .map(StoreCredit::operation)
+                .into_iter()
+                .collect()
+        }
+        Some(metafield) => {
+            let config: &DiscountConfig = metafield.json_value();
+
+            match &config.mode {
+                None => {
+                    log!("Config has no mode, applying the referral discount (legacy config)");
+                    referral_discount(&cart, config)
+                        .map(|referral| referral.operation)
+                        .into_iter()
+                        .collect()
+                }
+                Some(DiscountMode::Referral) => referral_discount(&cart, config)
+                    .map(|referral| referral.operation)
+                    .into_iter()
+                    .collect(),
+                Some(DiscountMode::StoreCredit) => {
+                    store_credit(&cart, Some(config), Money::zero(currency_code))
+                        .map(StoreCredit::operation)
+                        .into_iter()
+                        .collect()
+                }
+                Some(DiscountMode::Combined) => {
+                    // Credit pays for what's left after the referral discount,
+                    // so the two together never exceed the order
+                    let referral = referral_discount(&cart, config);
+                    let credit = store_credit(
+                        &cart,
+                        Some(config),
+                        referral
+                            .as_ref()
+                            .map_or(Money::zero(currency_code), |referral| referral.amount),
+                    );
+                    combined_operations(referral, credit)
+                }
+                Some(DiscountMode::Unknown(mode)) => {
+                    log!("Unknown discount mode {:?}, no discount applied", mode);
+                    vec![]
+                }
+            }
+        }
+    };
+
+    Ok(schema::CartLinesDiscountsGenerateRunResult { operations })
+}
+
+/// Cart values shared by the referral and store credit discounts.
+#[derive(Clone, Copy)]
+struct CartContext<'a> {
+    input: &'a schema::cart_lines_discounts_generate_run::Input,
+    currency_code: &'a str,
+    cart_subtotal: Money,
+    presentment_currency_rate: f64,
+    language: &'a str,
+    has_order_discount_class: bool,
+    has_product_discount_class: bool,
+}
+
+/// The referee discount for a validated referral.
+fn referral_discount(cart: &CartContext, config: &DiscountConfig) -> Option<ReferralDiscount> {
+    let CartContext {
+        input,
+        currency_code,
+        cart_subtotal,
+        presentment_currency_rate,
+        language,
+        has_order_discount_class,
+        has_product_discount_class,
+    } = *cart;
+
+    let local_time = input.shop().local_time();
+
+    let config = running_program(
+        config,
+        input
             .cart()
-            .referral_validated()
+            .referral_program()
             .and_then(|attr| attr.value())
-            .map(|v| v == "true")
-            .unwrap_or(false);
+            .map(|v| v.as_str()),
+        local_time.date(),
+        input
+            .discount()
+            .query_variables()
+            .map(|metafield| metafield.json_value()),
+        || *local_time.in_active_hours(),
+    )?;
 
-        if !referral_validated {
-            // No referral validated, don't apply discount
-            return Ok(schema::CartLinesDiscountsGenerateRunResult { operations: vec![] });
+    match check_referee(config, &Referral::from(input)) {
+        Ok(()) => {}
+        Err(RefereeRejection::NoReferral) => return None,
+        Err(rejection) => {
+            log!("Referral discount rejected: {}", rejection);
+            return None;
         }
+    }
 
-        // Check if referrer ID exists
-        let referrer_id = input
-            .cart()
-            .referrer_customer_id()
-            .and_then(|attr| attr.value());
+    // Line-level discounts need the PRODUCT class, order discounts need ORDER
+    let has_target_discount_class = match config.referee_discount_target {
+        RefereeDiscountTarget::Order => has_order_discount_class,
+        RefereeDiscountTarget::Products => has_product_discount_class,
+        RefereeDiscountTarget::Unknown => return None,
+    };
+
+    if !has_target_discount_class {
+        return None;
+    }
+
+    // Lines excluded from the order subtotal (gift cards, excluded products)
+    let excluded_lines: Vec<_> = input
+        .cart()
+        .lines()
+        .iter()
+        .filter(|line| is_excluded_line(line, config))
+        .collect();
+
+    // Cart lines the referee discount may apply to (product target only)
+    let eligible_lines: Vec<_> = input
+        .cart()
+        .lines()
+        .iter()
+        .filter(|line| is_referral_eligible_line(line, config))
+        .collect();
 
-        if referrer_id.is_none() {
-            return Ok(schema::CartLinesDiscountsGenerateRunResult { operations: vec![] });
+    let discountable_subtotal = match config.referee_discount_target {
+        RefereeDiscountTarget::Order => {
+            cart_subtotal - lines_subtotal(&excluded_lines, currency_code)
         }
+        RefereeDiscountTarget::Products => lines_subtotal(&eligible_lines, currency_code),
+        RefereeDiscountTarget::Unknown => return None,
+    };
 
-        // Get discount configuration from metafield
-        let config: &DiscountConfig = match input.discount().metafield() {
-            Some(metafield) => metafield.json_value(),
-            None => {
-                // No metafield configured, use defaults
-                return Ok(schema::CartLinesDiscountsGenerateRunResult { operations: vec![] });
-            }
-        };
+    if !discountable_subtotal.is_positive() {
+        // Nothing in the cart the referral discount applies to
+        return None;
+    }
 
-        // Check if cart meets minimum order requirement
-        if cart_subtotal < config.referee_min_order {
+    // Order discounts leave the minimum order to Shopify via discount conditions,
+    // so checkout can tell the buyer how much more to spend
+    let uses_conditions = config.referee_discount_target == RefereeDiscountTarget::Order
+        && !config.referee_min_order_in_function;
+
+    // Pick the discount tier for this cart
+    let (tier, meets_minimum) = match config.referee_tier_for(
+        discountable_subtotal,
+        presentment_currency_rate,
+        currency_code,
+    ) {
+        Some(tier) => (tier, true),
+        // Offer the lowest tier, held back by its condition until the cart qualifies
+        None if uses_conditions => (
+            config.referee_entry_tier(presentment_currency_rate, currency_code),
+            false,
+        ),
+        None => {
             // Cart doesn't meet minimum order requirement
-            return Ok(schema::CartLinesDiscountsGenerateRunResult { operations: vec![] });
+            return None;
         }
+    };
 
-        // Apply order discount
-        let discount_percentage = Decimal::from(config.referee_discount_percentage);
+    let (value, discount_label) = match config.referee_discount_type {
+        RefereeDiscountType::Percentage => {
+            let uncapped_amount = discountable_subtotal.percentage(tier.percentage);
 
-        let operations = vec![schema::CartOperation::OrderDiscountsAdd(
-            schema::OrderDiscountsAddOperation {
-                selection_strategy: schema::OrderDiscountSelectionStrategy::First,
-                candidates: vec![schema::OrderDiscountCandidate {
-                    targets: vec![schema::OrderDiscountCandidateTarget::OrderSubtotal(
-                        schema::OrderSubtotalTarget {
-                            excluded_cart_line_ids: vec![],
-                        },
-                    )],
-                    message: Some(format!(
-                        "Referral discount: {}% off",
-                        config.referee_discount_percentage
-                    )),
-                    value: schema::OrderDiscountCandidateValue::Percentage(schema::Percentage {
-                        value: discount_percentage,
-                    }),
-                    conditions: None,
-                    associated_discount_code: None,
-                }],
-            },
-        )];
+            let max_amount = config.referee_max_discount_amount.map(|max_amount| {
+                to_presentment(max_amount, presentment_currency_rate, currency_code)
+            });
 
-        return Ok(schema::CartLinesDiscountsGenerateRunResult { operations });
-    } else {
-        // STORE CREDIT DISCOUNT LOGIC
-        // Check if customer is logged in
-        let customer = match input
-            .cart()
-            .buyer_identity()
-            .and_then(|identity| identity.customer())
-        {
-            Some(c) => c,
-            None => {
-                // Customer not logged in, can't apply store credit
-                return Ok(schema::CartLinesDiscountsGenerateRunResult { operations: vec![] });
+            match max_amount {
+                // Cap binds: a percentage can't be capped natively, so emit the capped amount
+                Some(max_amount) if uncapped_amount > max_amount => (
+                    RefereeDiscountValue::FixedAmount(max_amount),
+                    format!(
+                        "{}% off (up to {})",
+                        tier.percentage,
+                        format_money(max_amount, currency_code)
+                    ),
+                ),
+                _ => (
+                    RefereeDiscountValue::Percentage(tier.percentage),
+                    format!("{}% off", tier.percentage),
+                ),
             }
-        };
+        }
+        RefereeDiscountType::FixedAmount => {
+            // Never discount more than the cart is worth
+            let discount_amount = tier.amount.min(discountable_subtotal);
 
-        // Get customer's referral credits from metafield
-        let credits_str = match customer.metafield() {
-            Some(m) => m.value().as_str(),
-            None => "0",
-        };
+            if !discount_amount.is_positive() {
+                return None;
+            }
 
-        let available_credits = credits_str.parse::<f64>().unwrap_or(0.0);
+            (
+                RefereeDiscountValue::FixedAmount(discount_amount),
+                format!("{} off", format_money(discount_amount, currency_code)),
+            )
+        }
+        RefereeDiscountType::Unknown => return None,
+    };
 
-        // If no credits available, don't apply discount
-        if available_credits <= 0.0 {
-            return Ok(schema::CartLinesDiscountsGenerateRunResult { operations: vec![] });
+    // What the discount takes off this cart (nothing until the minimum is met)
+    let discount_amount = match value {
+        _ if !meets_minimum => Money::zero(currency_code),
+        RefereeDiscountValue::Percentage(percentage) => {
+            discountable_subtotal.percentage(percentage)
         }
+        RefereeDiscountValue::FixedAmount(amount) => amount,
+    };
+
+    let message_kind = match config.referee_discount_type {
+        RefereeDiscountType::Percentage => MessageKind::ReferralPercentage,
+        RefereeDiscountType::FixedAmount => MessageKind::ReferralFixedAmount,
+        RefereeDiscountType::Unknown => return None,
+    };
 
-        // Apply discount up to available credits or cart subtotal (whichever is less)
-        let discount_amount = available_credits.min(cart_subtotal);
+    let message = match localized_template(&config.messages, language, message_kind) {
+        Some(template) => {
+            let amount = match value {
+                RefereeDiscountValue::Percentage(_) => String::new(),
+                RefereeDiscountValue::FixedAmount(amount) => format_money(amount, currency_code),
+            };
 
-        if discount_amount <= 0.0 {
-            return Ok(schema::CartLinesDiscountsGenerateRunResult { operations: vec![] });
+            render(
+                template,
+                &[
+                    ("percentage", &tier.percentage.to_string()),
+                    ("amount", &amount),
+                    ("minimum", &format_money(tier.min_subtotal, currency_code)),
+                    (
+                        "referrer_name",
+                        input
+                            .cart()
+                            .referrer_name()
+                            .and_then(|attr| attr.value())
+                            .map_or("", |name| name.as_str()),
+                    ),
+                ],
+            )
         }
+        // Report the unlocked tier when tiers are configured
+        None if config.referee_discount_tiers.is_empty() => {
+            format!("Referral discount: {}", discount_label)
+        }
+        None => format!(
+            "Referral discount: {} orders over {}",
+            discount_label,
+            format_money(tier.min_subtotal, currency_code)
+        ),
+    };
+
+    // Only order discounts leave lines out of their target
+    let mut order_excluded_cart_line_ids = None;
+
+    let operation = match config.referee_discount_target {
+        RefereeDiscountTarget::Order => {
+            // Apply order discount
+            let excluded_cart_line_ids: Vec<_> = excluded_lines
+                .iter()
+                .map(|line| line.id().clone())
+                .collect();
+            order_excluded_cart_line_ids = Some(excluded_cart_line_ids.clone());
+
+            let conditions = (uses_conditions && tier.shop_min_subtotal > 0.0).then(|| {
+                vec![schema::Condition::OrderMinimumSubtotal(
+                    schema::OrderMinimumSubtotal {
+                        excluded_cart_line_ids: excluded_cart_line_ids.clone(),
+                        minimum_amount: Decimal::from(tier.shop_min_subtotal),
+                    },
+                )]
+            });
 
-        // Convert to Decimal for the discount value
-        let discount_decimal = Decimal::from(discount_amount);
+            let value = match value {
+                RefereeDiscountValue::Percentage(percentage) => {
+                    schema::OrderDiscountCandidateValue::Percentage(schema::Percentage {
+                        value: Decimal::from(percentage),
+                    })
+                }
+                RefereeDiscountValue::FixedAmount(amount) => {
+                    schema::OrderDiscountCandidateValue::FixedAmount(schema::FixedAmount {
+                        amount: amount.to_decimal(),
+                    })
+                }
+            };
 
-        // Apply fixed amount discount
-        let operations = vec![schema::CartOperation::OrderDiscountsAdd(
-            schema::OrderDiscountsAddOperation {
+            schema::CartOperation::OrderDiscountsAdd(schema::OrderDiscountsAddOperation {
                 selection_strategy: schema::OrderDiscountSelectionStrategy::First,
                 candidates: vec![schema::OrderDiscountCandidate {
                     targets: vec![schema::OrderDiscountCandidateTarget::OrderSubtotal(
                         schema::OrderSubtotalTarget {
-                            excluded_cart_line_ids: vec![],
+                            excluded_cart_line_ids,
                         },
                     )],
-                    message: Some(format!("Store credit: ${:.2}", discount_amount)),
-                    value: schema::OrderDiscountCandidateValue::FixedAmount(
-                        schema::FixedAmount {
-                            amount: discount_decimal,
+                    message: Some(message.clone()),
+                    value,
+                    conditions,
+                    associated_discount_code: None,
+                }],
+            })
+        }
+        RefereeDiscountTarget::Products => {
+            // Apply product discount across the eligible lines
+            let value = match value {
+                RefereeDiscountValue::Percentage(percentage) => {
+                    schema::ProductDiscountCandidateValue::Percentage(schema::Percentage {
+                        value: Decimal::from(percentage),
+                    })
+                }
+                RefereeDiscountValue::FixedAmount(amount) => {
+                    schema::ProductDiscountCandidateValue::FixedAmount(
+                        schema::ProductDiscountCandidateFixedAmount {
+                            amount: amount.to_decimal(),
+                            applies_to_each_item: Some(false),
                         },
-                    ),
-                    conditions: None,
+                    )
+                }
+            };
+
+            schema::CartOperation::ProductDiscountsAdd(schema::ProductDiscountsAddOperation {
+                selection_strategy: schema::ProductDiscountSelectionStrategy::First,
+                candidates: vec![schema::ProductDiscountCandidate {
+                    targets: eligible_lines
+                        .iter()
+                        .map(|line| {
+                            schema::ProductDiscountCandidateTarget::CartLine(
+                                schema::CartLineTarget {
+                                    id: line.id().clone(),
+                                    quantity: None,
+                                },
+                            )
+                        })
+                        .collect(),
+                    message: Some(message.clone()),
+                    value,
                     associated_discount_code: None,
                 }],
-            },
-        )];
+            })
+        }
+        RefereeDiscountTarget::Unknown => return None,
+    };
+
+    Some(ReferralDiscount {
+        operation,
+        amount: discount_amount,
+        message,
+        order_excluded_cart_line_ids,
+    })
+}
+
+/// A referee discount worked out for a cart.
+struct ReferralDiscount {
+    operation: schema::CartOperation,
+    /// What the discount takes off the order (nothing until its minimum is met)
+    amount: Money,
+    message: String,
+    /// Lines left out of an order discount (`None` for product discounts)
+    order_excluded_cart_line_ids: Option<Vec<schema::Id>>,
+}
+
+/// The operations of a combined discount.
+///
+/// Shopify applies one order discount per run, so an order referral discount
+/// and store credit become a single fixed amount candidate worth both. A
+/// product referral discount stays on its lines next to the credit. An order
+/// referral discount still short of its minimum is dropped when there's
+/// credit to apply, as its condition would hold the credit back too.
+fn combined_operations(
+    referral: Option<ReferralDiscount>,
+    credit: Option<StoreCredit>,
+) -> Vec<schema::CartOperation> {
+    let (referral, credit) = match (referral, credit) {
+        (
+            Some(ReferralDiscount {
+                order_excluded_cart_line_ids: Some(referral_excluded),
+                amount,
+                message,
+                ..
+            }),
+            Some(credit),
+        ) => {
+            if !amount.is_positive() {
+                return vec![credit.operation()];
+            }
+
+            let mut excluded_cart_line_ids = referral_excluded;
+            for id in credit.excluded_cart_line_ids {
+                if !excluded_cart_line_ids.contains(&id) {
+                    excluded_cart_line_ids.push(id);
+                }
+            }
+
+            return vec![schema::CartOperation::OrderDiscountsAdd(
+                schema::OrderDiscountsAddOperation {
+                    selection_strategy: schema::OrderDiscountSelectionStrategy::First,
+                    candidates: vec![schema::OrderDiscountCandidate {
+                        targets: vec![schema::OrderDiscountCandidateTarget::OrderSubtotal(
+                            schema::OrderSubtotalTarget {
+                                excluded_cart_line_ids,
+                            },
+                        )],
+                        message: Some(format!("{} + {}", message, credit.message)),
+                        value: schema::OrderDiscountCandidateValue::FixedAmount(
+                            schema::FixedAmount {
+                                amount: (amount + credit.amount).to_decimal(),
+                            },
+                        ),
+                        conditions: None,
+                        associated_discount_code: None,
+                    }],
+                },
+            )];
+        }
+        other => other,
+    };
+
+    referral
+        .map(|referral| referral.operation)
+        .into_iter()
+        .chain(credit.map(StoreCredit::operation))
+        .collect()
+}
 
-        return Ok(schema::CartLinesDiscountsGenerateRunResult { operations });
+/// The store credit for a logged-in customer with a credit balance.
+///
+/// `config` supplies message templates and `excluded_product_types`; the
+/// store credit discount has no config, so only gift cards and excluded
+/// products are left out. `already_discounted` is the amount other discounts
+/// in this run take off the order; credit is limited to the subtotal that
+/// remains.
+fn store_credit(
+    cart: &CartContext,
+    config: Option<&DiscountConfig>,
+    already_discounted: Money,
+) -> Option<StoreCredit> {
+    let CartContext {
+        input,
+        currency_code,
+        cart_subtotal,
+        presentment_currency_rate,
+        language,
+        has_order_discount_class,
+        ..
+    } = *cart;
+
+    // Store credit is always an order discount
+    if !has_order_discount_class {
+        return None;
+    }
+
+    // Check if customer is logged in
+    let customer = match input
+        .cart()
+        .buyer_identity()
+        .and_then(|identity| identity.customer())
+    {
+        Some(c) => c,
+        None => {
+            // Customer not logged in, can't apply store credit
+            return None;
+        }
+    };
+
+    // Get customer's referral credits from metafield
+    let balance = match customer.metafield() {
+        Some(m) => match m.json_value().balance() {
+            Ok(balance) => balance,
+            Err(error) => {
+                log!(
+                    "Store credit ignored: invalid referral_credits metafield: {}",
+                    error
+                );
+                return None;
+            }
+        },
+        None => return None,
+    };
+
+    let today = input.shop().local_time().date();
+    let shop_currency = input
+        .shop()
+        .shop_currency()
+        .map(|metafield| metafield.value().as_str());
+    let available_credits = balance.available(
+        today,
+        shop_currency,
+        presentment_currency_rate,
+        currency_code,
+    );
+
+    // If no credits available, don't apply discount
+    if !available_credits.is_positive() {
+        return None;
     }
+
+    // Store credit doesn't pay for gift cards or excluded products
+    let default_config = DiscountConfig::default();
+    let exclusion_config = config.unwrap_or(&default_config);
+    let excluded_lines: Vec<_> = input
+        .cart()
+        .lines()
+        .iter()
+        .filter(|line| is_excluded_line(line, exclusion_config))
+        .collect();
+
+    let eligible_subtotal =
+        (cart_subtotal - lines_subtotal(&excluded_lines, currency_code) - already_discounted)
+            .max(Money::zero(currency_code));
+
+    // Merchants can set a minimum spend and limit how much of the order credit may pay for
+    let default_store_credit_config = StoreCreditConfig::default();
+    let store_credit_config = input
+        .discount()
+        .store_credit_config()
+        .map(|metafield| metafield.json_value())
+        .unwrap_or(&default_store_credit_config);
+
+    let max_credit = match store_credit_config.credit_limit(
+        eligible_subtotal,
+        presentment_currency_rate,
+        currency_code,
+    ) {
+        Some(limit) => limit,
+        None => {
+            // Below the minimum subtotal for redeeming credit
+            return None;
+        }
+    };
+
+    // Shoppers can save credits for later by requesting a smaller amount
+    // (in the cart's currency); without a request the full balance is used
+    let requested_credit = match input
+        .cart()
+        .store_credit_requested()
+        .and_then(|attr| attr.value())
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+    {
+        Some(value) => match Money::parse(value, currency_code) {
+            Some(amount) if !amount.is_negative() => Some(amount),
+            _ => {
+                // Don't spend credit the shopper may have meant to keep
+                log!(
+                    "Store credit ignored: invalid store_credit_requested {:?}",
+                    value
+                );
+                return None;
+            }
+        },
+        None => None,
+    };
+
+    // Apply the lesser of the requested amount, available credits and the credit limit,
+    // spending the credit that expires soonest first
+    let discount_amount = balance.spend(
+        today,
+        requested_credit.map_or(max_credit, |requested| requested.min(max_credit)),
+        shop_currency,
+        presentment_currency_rate,
+        currency_code,
+    );
+
+    if !discount_amount.is_positive() {
+        return None;
+    }
+
+    let amount = format_money(discount_amount, currency_code);
+    let message = config
+        .and_then(|config| localized_template(&config.messages, language, MessageKind::StoreCredit))
+        .map(|template| render(template, &[("amount", &amount)]))
+        .unwrap_or_else(|| format!("Store credit: {}", amount));
+
+    Some(StoreCredit {
+        amount: discount_amount,
+        message,
+        excluded_cart_line_ids: excluded_lines
+            .iter()
+            .map(|line| line.id().clone())
+            .collect(),
+    })
+}
+
+/// Store credit worked out for a cart.
+struct StoreCredit {
+    amount: Money,
+    message: String,
+    /// Lines store credit doesn't pay for
+    excluded_cart_line_ids: Vec<schema::Id>,
+}
+
+impl StoreCredit {
+    /// The store credit as a fixed amount order discount.
+    fn operation(self) -> schema::CartOperation {
+        schema::CartOperation::OrderDiscountsAdd(schema::OrderDiscountsAddOperation {
+            selection_strategy: schema::OrderDiscountSelectionStrategy::First,
+            candidates: vec![schema::OrderDiscountCandidate {
+                targets: vec![schema::OrderDiscountCandidateTarget::OrderSubtotal(
+                    schema::OrderSubtotalTarget {
+                        excluded_cart_line_ids: self.excluded_cart_line_ids,
+                    },
+                )],
+                message: Some(self.message),
+                value: schema::OrderDiscountCandidateValue::FixedAmount(schema::FixedAmount {
+                    amount: self.amount.to_decimal(),
+                }),
+                conditions: None,
+                associated_discount_code: None,
+            }],
+        })
+    }
+}
+
+/// Whether a cart line is excluded from referral and store credit discounts.
+///
+/// Gift cards, products tagged `daisychain-excluded` or with one of the
+/// `excludedTags` query variables, and products whose type is listed in
+/// `excluded_product_types` are excluded.
+fn is_excluded_line(
+    line: &schema::cart_lines_discounts_generate_run::input::cart::Lines,
+    config: &DiscountConfig,
+) -> bool {
+    let product = match line.merchandise() {
+        schema::cart_lines_discounts_generate_run::input::cart::lines::Merchandise::ProductVariant(
+            variant,
+        ) => variant.product(),
+        _ => return false,
+    };
+
+    if *product.is_gift_card() || *product.referral_excluded() || *product.has_excluded_tag() {
+        return true;
+    }
+
+    product.product_type().as_ref().is_some_and(|product_type| {
+        config
+            .excluded_product_types
+            .iter()
+            .any(|excluded| excluded.eq_ignore_ascii_case(product_type))
+    })
+}
+
+/// Whether the referee discount may apply to a cart line (product target).
+///
+/// Custom products and excluded lines never qualify. When
+/// `referee_require_eligible_tag` is set, only products tagged
+/// `daisychain-eligible` or in one of the `eligibleCollections` query
+/// variables do.
+fn is_referral_eligible_line(
+    line: &schema::cart_lines_discounts_generate_run::input::cart::Lines,
+    config: &DiscountConfig,
+) -> bool {
+    let product = match line.merchandise() {
+        schema::cart_lines_discounts_generate_run::input::cart::lines::Merchandise::ProductVariant(
+            variant,
+        ) => variant.product(),
+        _ => return false,
+    };
+
+    if is_excluded_line(line, config) {
+        return false;
+    }
+
+    !config.referee_require_eligible_tag
+        || *product.referral_eligible()
+        || *product.in_eligible_collection()
+}
+
+/// Sum of the subtotals of the given cart lines.
+fn lines_subtotal(
+    lines: &[&schema::cart_lines_discounts_generate_run::input::cart::Lines],
+    currency_code: &str,
+) -> Money {
+    lines
+        .iter()
+        .fold(Money::zero(currency_code), |subtotal, line| {
+            subtotal
+                + Money::from_decimal(
+                    line.cost().subtotal_amount().amount().as_f64(),
+                    currency_code,
+                )
+        })
 }
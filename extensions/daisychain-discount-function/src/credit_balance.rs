@@ -0,0 +1,507 @@
+use crate::dates::iso_date_part;
+use crate::money::Money;
+use shopify_function::prelude::*;
+use shopify_function::wasm_api::{Deserialize, Value};
+use std::fmt;
+
+/// Why a `referral_credits` metafield couldn't be read.
+#[derive(Debug, PartialEq)]
+pub enum CreditBalanceError {
+    Malformed(String),
+    NotFinite(String),
+    Negative(String),
+    MissingAmount,
+    InvalidDate(String),
+}
+
+impl fmt::Display for CreditBalanceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(value) => write!(f, "malformed amount {:?}", value),
+            Self::NotFinite(value) => write!(f, "non-finite amount {:?}", value),
+            Self::Negative(value) => write!(f, "negative amount {:?}", value),
+            Self::MissingAmount => f.write_str("balance has no amount"),
+            Self::InvalidDate(value) => write!(f, "invalid grant date {:?}", value),
+        }
+    }
+}
+
+/// A customer's store credit balance from the `referral_credits` metafield.
+///
+/// The metafield holds either a decimal amount (`"25.00"`, in the shop's
+/// currency), a JSON balance whose `buckets` are the parts of `amount` that
+/// expire:
+///
+/// ```json
+/// {"amount": "25.00", "currency": "USD", "buckets": [{"amount": "10.00", "expires_at": "2025-01-31"}]}
+/// ```
+///
+/// or a JSON list of credit grants:
+///
+/// ```json
+/// [{"amount": "10.00", "available_at": "2025-01-10", "expires_at": "2025-03-31"}]
+/// ```
+///
+/// Dates are compared with the shop's local date: a grant can be spent from its
+/// `available_at` date through its `expires_at` date. `currency` defaults to
+/// the shop's currency; credit in any currency other than the shop's or the
+/// cart's can't be converted and is left out.
+#[derive(Debug, PartialEq)]
+pub struct CreditBalance {
+    /// The `amount` of the decimal and object forms, which includes the grants
+    total: Option<CreditGrant>,
+    grants: Vec<CreditGrant>,
+}
+
+#[derive(Debug, PartialEq)]
+struct CreditGrant {
+    /// At the precision it was written in, rounded once when converted
+    amount: Money,
+    currency: Option<String>,
+    available_at: Option<String>,
+    expires_at: Option<String>,
+}
+
+impl CreditGrant {
+    fn read(value: &Value, currency: Option<&String>) -> Result<Self, CreditBalanceError> {
+        let amount = value.get_obj_prop("amount");
+        if amount.is_null() {
+            return Err(CreditBalanceError::MissingAmount);
+        }
+
+        Ok(Self {
+            amount: read_amount(&amount)?,
+            currency: value
+                .get_obj_prop("currency")
+                .as_string()
+                .or_else(|| currency.cloned()),
+            available_at: read_date(&value.get_obj_prop("available_at"))?,
+            expires_at: read_date(&value.get_obj_prop("expires_at"))?,
+        })
+    }
+
+    /// Whether the grant can be spent on `today`.
+    fn is_spendable(&self, today: &str) -> bool {
+        // ISO dates compare correctly as strings
+        self.available_at
+            .as_deref()
+            .is_none_or(|available_at| today >= available_at)
+            && self
+                .expires_at
+                .as_deref()
+                .is_none_or(|expires_at| today <= expires_at)
+    }
+
+    /// The grant in the cart's currency, or `None` when it's in a currency
+    /// `presentment_currency_rate` doesn't convert from.
+    fn to_presentment(
+        &self,
+        shop_currency: Option<&str>,
+        presentment_currency_rate: f64,
+        currency_code: &str,
+    ) -> Option<Money> {
+        let rate = match self.currency.as_deref() {
+            // Credit kept in the cart's currency needs no conversion
+            Some(currency) if currency.eq_ignore_ascii_case(currency_code) => 1.0,
+            None => presentment_currency_rate,
+            Some(currency)
+                if shop_currency.is_some_and(|shop| currency.eq_ignore_ascii_case(shop)) =>
+            {
+                presentment_currency_rate
+            }
+            Some(_) => return None,
+        };
+
+        Some(self.amount.convert(rate, currency_code))
+    }
+}
+
+impl CreditBalance {
+    /// The balance that can be spent on `today` (an ISO date in the shop's
+    /// time zone), in the cart's currency.
+    pub fn available(
+        &self,
+        today: &str,
+        shop_currency: Option<&str>,
+        presentment_currency_rate: f64,
+        currency_code: &str,
+    ) -> Money {
+        self.spendable(
+            today,
+            shop_currency,
+            presentment_currency_rate,
+            currency_code,
+        )
+        .into_iter()
+        .fold(Money::zero(currency_code), |total, amount| total + amount)
+    }
+
+    /// Spends up to `limit` on `today`, taking from the grants that expire
+    /// soonest first. Returns the amount spent.
+    pub fn spend(
+        &self,
+        today: &str,
+        limit: Money,
+        shop_currency: Option<&str>,
+        presentment_currency_rate: f64,
+        currency_code: &str,
+    ) -> Money {
+        let zero = Money::zero(currency_code);
+
+        self.spendable(
+            today,
+            shop_currency,
+            presentment_currency_rate,
+            currency_code,
+        )
+        .into_iter()
+        .fold(zero, |spent, amount| {
+            spent + amount.min((limit - spent).max(zero))
+        })
+    }
+
+    /// The amounts that can be spent on `today`, oldest-expiring first.
+    fn spendable(
+        &self,
+        today: &str,
+        shop_currency: Option<&str>,
+        presentment_currency_rate: f64,
+        currency_code: &str,
+    ) -> Vec<Money> {
+        let zero = Money::zero(currency_code);
+
+        let mut grants: Vec<&CreditGrant> = self.grants.iter().collect();
+        // Grants without an expiry go last
+        grants.sort_by_key(|grant| (grant.expires_at.is_none(), grant.expires_at.as_deref()));
+
+        let mut granted = zero;
+        let mut spendable = Vec::with_capacity(grants.len() + 1);
+
+        for grant in grants {
+            let Some(amount) =
+                grant.to_presentment(shop_currency, presentment_currency_rate, currency_code)
+            else {
+                log!(
+                    "Store credit grant ignored: {:?} credit can't be converted to {}",
+                    grant.currency,
+                    currency_code
+                );
+                continue;
+            };
+            granted = granted + amount;
+
+            if grant.is_spendable(today) {
+                spendable.push(amount);
+            }
+        }
+
+        // The part of the total outside any grant never expires
+        if let Some(total) = &self.total {
+            match total.to_presentment(shop_currency, presentment_currency_rate, currency_code) {
+                Some(total) => spendable.push((total - granted).max(zero)),
+                None => log!(
+                    "Store credit balance ignored: {:?} credit can't be converted to {}",
+                    total.currency,
+                    currency_code
+                ),
+            }
+        }
+
+        spendable
+    }
+
+    fn read(value: &Value) -> Result<Self, CreditBalanceError> {
+        if value.is_array() {
+            return Ok(Self {
+                total: None,
+                grants: read_grants(value, None)?,
+            });
+        }
+
+        if !value.is_obj() {
+            return Ok(Self {
+                total: Some(CreditGrant {
+                    amount: read_amount(value)?,
+                    currency: None,
+                    available_at: None,
+                    expires_at: None,
+                }),
+                grants: vec![],
+            });
+        }
+
+        let total = CreditGrant::read(value, None)?;
+        let grants = read_grants(&value.get_obj_prop("buckets"), total.currency.as_ref())?;
+
+        Ok(Self {
+            total: Some(total),
+            grants,
+        })
+    }
+}
+
+/// The `jsonValue` of the `referral_credits` metafield.
+///
+/// A malformed balance doesn't fail the whole function run; the error is kept
+/// so the caller can log it and skip store credit.
+pub struct CreditBalanceValue(Result<CreditBalance, CreditBalanceError>);
+
+impl CreditBalanceValue {
+    pub fn balance(&self) -> Result<&CreditBalance, &CreditBalanceError> {
+        self.0.as_ref()
+    }
+}
+
+impl Deserialize for CreditBalanceValue {
+    fn deserialize(
+        value: &Value,
+    ) -> std::result::Result<Self, shopify_function::wasm_api::read::Error> {
+        Ok(Self(CreditBalance::read(value)))
+    }
+}
+
+fn read_grants(
+    value: &Value,
+    currency: Option<&String>,
+) -> Result<Vec<CreditGrant>, CreditBalanceError> {
+    (0..value.array_len().unwrap_or(0))
+        .map(|i| CreditGrant::read(&value.get_at_index(i), currency))
+        .collect()
+}
+
+/// Reads an optional ISO date or date-time, keeping the date.
+fn read_date(value: &Value) -> Result<Option<String>, CreditBalanceError> {
+    if value.is_null() {
+        return Ok(None);
+    }
+
+    let date = value.as_string().unwrap_or_default();
+
+    match iso_date_part(&date) {
+        Some(day) => Ok(Some(day.to_string())),
+        None => Err(CreditBalanceError::InvalidDate(date)),
+    }
+}
+
+/// Reads a decimal string (as `number_decimal` metafields are stored) or a
+/// JSON number.
+fn read_amount(value: &Value) -> Result<Money, CreditBalanceError> {
+    match (value.as_string(), value.as_number()) {
+        (Some(amount), _) => parse_amount(amount.trim()),
+        // Displayed without an exponent, so it reads back exactly
+        (None, Some(amount)) => parse_amount(&amount.to_string()),
+        (None, None) => Err(CreditBalanceError::Malformed(String::new())),
+    }
+}
+
+fn parse_amount(value: &str) -> Result<Money, CreditBalanceError> {
+    // Told apart from other malformed amounts so the log says what went wrong
+    if value.parse::<f64>().is_ok_and(|amount| !amount.is_finite()) {
+        return Err(CreditBalanceError::NotFinite(value.to_string()));
+    }
+
+    match Money::parse_exact(value) {
+        Some(amount) if amount.is_negative() => {
+            Err(CreditBalanceError::Negative(value.to_string()))
+        }
+        Some(amount) => Ok(amount),
+        None => Err(CreditBalanceError::Malformed(value.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use shopify_function::wasm_api::Context;
+
+    fn read(input: serde_json::Value) -> Result<CreditBalance, CreditBalanceError> {
+        let context = Context::new_with_input(input);
+        CreditBalance::read(&context.input_get().unwrap())
+    }
+
+    const SHOP: Option<&str> = Some("USD");
+
+    fn usd(amount: &str) -> Money {
+        Money::parse(amount, "USD").unwrap()
+    }
+
+    #[test]
+    fn reads_decimal_balances() {
+        let balance = read(json!("25.00")).unwrap();
+        assert_eq!(
+            balance.available("2025-01-15", SHOP, 1.0, "USD"),
+            usd("25.00")
+        );
+
+        let balance = read(json!(12.5)).unwrap();
+        assert_eq!(
+            balance.available("2025-01-15", SHOP, 1.0, "USD"),
+            usd("12.50")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_amounts() {
+        for (input, error) in [
+            (json!("5,00"), CreditBalanceError::Malformed("5,00".into())),
+            (json!("1e3"), CreditBalanceError::Malformed("1e3".into())),
+            (json!(true), CreditBalanceError::Malformed(String::new())),
+            (json!("NaN"), CreditBalanceError::NotFinite("NaN".into())),
+            (json!("inf"), CreditBalanceError::NotFinite("inf".into())),
+            (json!("-inf"), CreditBalanceError::NotFinite("-inf".into())),
+            (json!("-3"), CreditBalanceError::Negative("-3".into())),
+            (json!(-3), CreditBalanceError::Negative("-3".into())),
+            (
+                json!({"amount": "-0.01"}),
+                CreditBalanceError::Negative("-0.01".into()),
+            ),
+        ] {
+            assert_eq!(read(input.clone()), Err(error), "{input}");
+        }
+    }
+
+    #[test]
+    fn rejects_grants_without_amount_or_with_invalid_dates() {
+        assert_eq!(
+            read(json!({"currency": "USD"})),
+            Err(CreditBalanceError::MissingAmount)
+        );
+        assert_eq!(
+            read(json!([{"expires_at": "2025-01-31"}])),
+            Err(CreditBalanceError::MissingAmount)
+        );
+        assert_eq!(
+            read(json!([{"amount": "5.00", "expires_at": "31/01/2025"}])),
+            Err(CreditBalanceError::InvalidDate("31/01/2025".into()))
+        );
+        assert_eq!(
+            read(
+                json!({"amount": "5.00", "buckets": [{"amount": "1.00", "available_at": 20250131}]})
+            ),
+            Err(CreditBalanceError::InvalidDate(String::new()))
+        );
+    }
+
+    #[test]
+    fn expiring_buckets_are_part_of_the_total() {
+        let balance = read(json!({
+            "amount": "25.00",
+            "buckets": [{"amount": "10.00", "expires_at": "2025-01-31T23:59:59Z"}]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            balance.spendable("2025-01-31", SHOP, 1.0, "USD"),
+            vec![usd("10.00"), usd("15.00")]
+        );
+        assert_eq!(
+            balance.spendable("2025-02-01", SHOP, 1.0, "USD"),
+            vec![usd("15.00")]
+        );
+        assert_eq!(
+            balance.available("2025-02-01", SHOP, 1.0, "USD"),
+            usd("15.00")
+        );
+
+        // Buckets adding up to more than the amount leave no non-expiring remainder
+        let balance = read(json!({
+            "amount": "5.00",
+            "buckets": [{"amount": "8.00", "expires_at": "2025-01-31"}]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            balance.spendable("2025-01-15", SHOP, 1.0, "USD"),
+            vec![usd("8.00"), usd("0.00")]
+        );
+        assert_eq!(
+            balance.available("2025-02-01", SHOP, 1.0, "USD"),
+            usd("0.00")
+        );
+    }
+
+    #[test]
+    fn spends_soonest_expiring_grants_first() {
+        let balance = read(json!([
+            {"amount": "5.00", "expires_at": "2025-03-31"},
+            {"amount": "7.00"},
+            {"amount": "10.00", "expires_at": "2025-01-31"},
+            {"amount": "20.00", "available_at": "2025-02-01"}
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            balance.spendable("2025-01-15", SHOP, 1.0, "USD"),
+            vec![usd("10.00"), usd("5.00"), usd("7.00")]
+        );
+        assert_eq!(
+            balance.spend("2025-01-15", usd("12.00"), SHOP, 1.0, "USD"),
+            usd("12.00")
+        );
+        assert_eq!(
+            balance.spend("2025-01-15", usd("50.00"), SHOP, 1.0, "USD"),
+            usd("22.00")
+        );
+        assert_eq!(
+            balance.available("2025-02-01", SHOP, 1.0, "USD"),
+            usd("32.00")
+        );
+    }
+
+    #[test]
+    fn converts_only_credit_in_another_currency() {
+        let balance = read(json!([
+            {"amount": "10.00", "currency": "EUR"},
+            {"amount": "10.00"}
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            balance.available("2025-01-15", SHOP, 0.9, "EUR"),
+            Money::parse("19.00", "EUR").unwrap()
+        );
+    }
+
+    #[test]
+    fn leaves_out_credit_in_a_third_currency() {
+        let balance = read(json!([
+            {"amount": "10.00", "currency": "GBP"},
+            {"amount": "10.00", "currency": "usd"},
+            {"amount": "5.00", "currency": "EUR"}
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            balance.available("2025-01-15", SHOP, 0.9, "EUR"),
+            Money::parse("14.00", "EUR").unwrap()
+        );
+
+        // Without the shop's currency only credit in the cart's currency is known
+        assert_eq!(
+            balance.available("2025-01-15", None, 0.9, "EUR"),
+            Money::parse("5.00", "EUR").unwrap()
+        );
+
+        let balance = read(json!({"amount": "25.00", "currency": "GBP"})).unwrap();
+        assert_eq!(
+            balance.available("2025-01-15", SHOP, 0.9, "EUR"),
+            Money::zero("EUR")
+        );
+    }
+
+    #[test]
+    fn reads_grants_exactly() {
+        let balance = read(json!([{"amount": "0.105"}, {"amount": "0.105"}])).unwrap();
+
+        // Each grant is rounded once when converted
+        assert_eq!(
+            balance.available("2025-01-15", SHOP, 1.0, "USD"),
+            usd("0.22")
+        );
+        assert_eq!(
+            balance.available("2025-01-15", SHOP, 3.0, "USD"),
+            usd("0.64")
+        );
+    }
+}
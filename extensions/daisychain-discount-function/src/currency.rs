@@ -0,0 +1,72 @@
+use crate::money::Money;
+
+/// Converts an amount in the shop's currency to the buyer's presentment currency.
+///
+/// Config values and store credit balances are stored in the shop's currency,
+/// while cart amounts and fixed-amount discounts are in the cart's currency.
+pub fn to_presentment(
+    shop_amount: f64,
+    presentment_currency_rate: f64,
+    currency_code: &str,
+) -> Money {
+    Money::from_converted(shop_amount, presentment_currency_rate, currency_code)
+}
+
+/// Formats an amount in the given currency for checkout messages, e.g. `$10.00`,
+/// `€10.00` or `¥1000`. Unknown currencies fall back to `10.00 XYZ`.
+pub fn format_money(amount: Money, currency_code: &str) -> String {
+    let sign = if amount.is_negative() { "-" } else { "" };
+    let minor_units = amount.minor_units().unsigned_abs();
+    let scale = 10u64.pow(amount.decimals());
+
+    let number = match amount.decimals() {
+        0 => minor_units.to_string(),
+        decimals => format!(
+            "{}.{:0width$}",
+            minor_units / scale,
+            minor_units % scale,
+            width = decimals as usize
+        ),
+    };
+
+    match symbol(currency_code) {
+        Some(symbol) => format!("{}{}{}", sign, symbol, number),
+        None => format!("{}{} {}", sign, number, currency_code),
+    }
+}
+
+/// Number of decimal places used when displaying amounts in a currency.
+pub fn minor_units(currency_code: &str) -> usize {
+    match currency_code {
+        "BIF" | "CLP" | "DJF" | "GNF" | "ISK" | "JPY" | "KMF" | "KRW" | "PYG" | "RWF" | "UGX"
+        | "VND" | "VUV" | "XAF" | "XOF" | "XPF" => 0,
+        "BHD" | "IQD" | "JOD" | "KWD" | "LYD" | "OMR" | "TND" => 3,
+        _ => 2,
+    }
+}
+
+fn symbol(currency_code: &str) -> Option<&'static str> {
+    let symbol = match currency_code {
+        "USD" => "$",
+        "CAD" => "CA$",
+        "AUD" => "A$",
+        "NZD" => "NZ$",
+        "HKD" => "HK$",
+        "SGD" => "S$",
+        "MXN" => "MX$",
+        "EUR" => "€",
+        "GBP" => "£",
+        "JPY" => "¥",
+        "CNY" => "CN¥",
+        "INR" => "₹",
+        "KRW" => "₩",
+        "BRL" => "R$",
+        "ILS" => "₪",
+        "PHP" => "₱",
+        "TRY" => "₺",
+        "VND" => "₫",
+        _ => return None,
+    };
+
+    Some(symbol)
+}
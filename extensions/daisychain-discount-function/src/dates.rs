@@ -0,0 +1,82 @@
+/// Whether `value` is an ISO date such as `2025-01-31`.
+pub fn is_iso_date(value: &str) -> bool {
+    value.len() == 10
+        && value.char_indices().all(|(i, c)| match i {
+            4 | 7 => c == '-',
+            _ => c.is_ascii_digit(),
+        })
+}
+
+/// The date part of an ISO date or date-time, e.g. `2025-01-31` for
+/// `2025-01-31T09:00:00Z`.
+pub fn iso_date_part(value: &str) -> Option<&str> {
+    let date = value.get(..10)?;
+    let rest = &value[10..];
+
+    (is_iso_date(date) && (rest.is_empty() || rest.starts_with('T'))).then_some(date)
+}
+
+/// The ISO date `days` after `date`, e.g. `2025-02-05` for `2025-01-31` plus 5.
+pub fn add_days(date: &str, days: i64) -> Option<String> {
+    let (year, month, day) = civil_from_days(parse_days(date)?.checked_add(days)?);
+
+    (0..=9999)
+        .contains(&year)
+        .then(|| format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+/// The day of the week of an ISO date, as `"mon"` to `"sun"`.
+pub fn weekday(date: &str) -> Option<&'static str> {
+    const WEEKDAYS: [&str; 7] = ["thu", "fri", "sat", "sun", "mon", "tue", "wed"];
+
+    // 1970-01-01 was a Thursday
+    Some(WEEKDAYS[parse_days(date)?.rem_euclid(7) as usize])
+}
+
+/// Days since 1970-01-01 of an ISO date.
+fn parse_days(date: &str) -> Option<i64> {
+    if !is_iso_date(date) {
+        return None;
+    }
+
+    let year: i64 = date[..4].parse().ok()?;
+    let month: i64 = date[5..7].parse().ok()?;
+    let day: i64 = date[8..].parse().ok()?;
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day))
+}
+
+// Days since 1970-01-01 in the proleptic Gregorian calendar
+// (http://howardhinnant.github.io/date_algorithms.html)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
@@ -0,0 +1,16 @@
+/// Lowercase hex of `bytes`, e.g. `0aff` for `[0x0a, 0xff]`.
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The bytes of a hex string (either case), or `None` if it isn't one.
+pub fn decode(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -3,6 +3,15 @@ use std::process;
 
 pub mod cart_delivery_options_discounts_generate_run;
 pub mod cart_lines_discounts_generate_run;
+pub mod credit_balance;
+pub mod currency;
+pub mod dates;
+pub mod hex;
+pub mod messages;
+pub mod money;
+pub mod referee;
+pub mod referral_token;
+pub mod self_referral;
 
 #[typegen("schema.graphql")]
 pub mod schema {
@@ -10,11 +19,23 @@ pub mod schema {
         "src/cart_lines_discounts_generate_run.graphql",
         custom_scalar_overrides = {
             "Input.discount.metafield.jsonValue" => super::cart_lines_discounts_generate_run::DiscountConfig,
+            "Input.cart.buyerIdentity.customer.metafield.jsonValue" => super::credit_balance::CreditBalanceValue,
+            "Input.cart.buyerIdentity.customer.usedReferral.jsonValue" => super::cart_lines_discounts_generate_run::UsedReferral,
+            "Input.discount.queryVariables.jsonValue" => super::cart_lines_discounts_generate_run::QueryVariables,
+            "Input.discount.storeCreditConfig.jsonValue" => super::cart_lines_discounts_generate_run::StoreCreditConfig,
         }
     )]
     pub mod cart_lines_discounts_generate_run {}
 
-    #[query("src/cart_delivery_options_discounts_generate_run.graphql")]
+    #[query(
+        "src/cart_delivery_options_discounts_generate_run.graphql",
+        custom_scalar_overrides = {
+            "Input.discount.metafield.jsonValue" => super::cart_lines_discounts_generate_run::DiscountConfig,
+            "Input.cart.buyerIdentity.customer.metafield.jsonValue" => super::credit_balance::CreditBalanceValue,
+            "Input.cart.buyerIdentity.customer.usedReferral.jsonValue" => super::cart_lines_discounts_generate_run::UsedReferral,
+            "Input.discount.queryVariables.jsonValue" => super::cart_lines_discounts_generate_run::QueryVariables,
+        }
+    )]
     pub mod cart_delivery_options_discounts_generate_run {}
 }
 
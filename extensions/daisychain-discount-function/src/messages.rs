@@ -0,0 +1,74 @@
+use shopify_function::prelude::*;
+use std::collections::HashMap;
+
+/// Checkout message templates for one language, from the config metafield.
+///
+/// Templates may use `{percentage}`, `{amount}`, `{minimum}` and
+/// `{referrer_name}` placeholders. Missing templates fall back to English.
+#[derive(Deserialize, Default, PartialEq)]
+pub struct MessageTemplates {
+    #[shopify_function(default)]
+    pub referral_percentage: Option<String>,
+    #[shopify_function(default)]
+    pub referral_fixed_amount: Option<String>,
+    #[shopify_function(default)]
+    pub store_credit: Option<String>,
+    #[shopify_function(default)]
+    pub shipping: Option<String>,
+}
+
+/// The candidate message being rendered.
+#[derive(Clone, Copy)]
+pub enum MessageKind {
+    ReferralPercentage,
+    ReferralFixedAmount,
+    StoreCredit,
+    Shipping,
+}
+
+impl MessageTemplates {
+    fn get(&self, kind: MessageKind) -> Option<&str> {
+        match kind {
+            MessageKind::ReferralPercentage => self.referral_percentage.as_deref(),
+            MessageKind::ReferralFixedAmount => self.referral_fixed_amount.as_deref(),
+            MessageKind::StoreCredit => self.store_credit.as_deref(),
+            MessageKind::Shipping => self.shipping.as_deref(),
+        }
+    }
+}
+
+/// Finds the template for the buyer's language.
+///
+/// Tries the exact language (`PT_BR` matches a `pt-BR` key), then its primary
+/// language (`pt`), then English. Returns `None` when no template is configured,
+/// in which case the built-in English message is used.
+pub fn localized_template<'a>(
+    messages: &'a HashMap<String, MessageTemplates>,
+    language: &str,
+    kind: MessageKind,
+) -> Option<&'a str> {
+    let language = normalize_language(language);
+    let primary_language = language.split('-').next().unwrap_or_default();
+
+    [language.as_str(), primary_language, "en"]
+        .iter()
+        .find_map(|candidate| {
+            messages
+                .iter()
+                .find(|(key, _)| normalize_language(key) == *candidate)
+                .and_then(|(_, templates)| templates.get(kind))
+        })
+}
+
+/// Replaces `{name}` placeholders in a template with their values.
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |message, (name, value)| {
+            message.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+fn normalize_language(language: &str) -> String {
+    language.to_ascii_lowercase().replace('_', "-")
+}
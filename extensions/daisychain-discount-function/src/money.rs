@@ -0,0 +1,391 @@
+use crate::currency::minor_units;
+use shopify_function::scalars::Decimal;
+use std::cmp::Ordering;
+use std::ops::{Add, Sub};
+
+// Digits kept when reading decimal strings (fits an i128 mantissa). Fractional
+// digits past the limit are dropped.
+const MAX_INTEGER_DIGITS: usize = 24;
+const MAX_FRACTION_DIGITS: usize = 12;
+
+/// An exact amount of money, stored in the currency's minor units (cents for
+/// USD, yen for JPY).
+///
+/// Amounts are rounded half-up (away from zero) to the currency's minor units
+/// whenever they're created from a decimal or scaled by a percentage or rate,
+/// so `19.99 * 15%` is `3.00` and `100.00 - 80.01` is `19.99`. All amounts in a
+/// function run are in the cart's presentment currency.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Money {
+    minor_units: i64,
+    decimals: u32,
+}
+
+impl Money {
+    pub fn zero(currency_code: &str) -> Self {
+        Self {
+            minor_units: 0,
+            decimals: decimals(currency_code),
+        }
+    }
+
+    /// Reads a decimal string such as `"19.99"`, `"-3"` or `"0.125"`.
+    ///
+    /// Returns `None` for anything else, including exponents, thousands
+    /// separators, `NaN` and `inf`.
+    pub fn parse(value: &str, currency_code: &str) -> Option<Self> {
+        let (mantissa, scale) = parse_decimal(value)?;
+        let decimals = decimals(currency_code);
+
+        Some(Self {
+            minor_units: i64::try_from(round_half_up(mantissa, scale, decimals)?).ok()?,
+            decimals,
+        })
+    }
+
+    /// Reads a decimal string at the precision it's written in, for amounts
+    /// whose currency isn't known until later. [`Money::convert`] rounds it to
+    /// a currency's minor units.
+    pub fn parse_exact(value: &str) -> Option<Self> {
+        let (mantissa, scale) = parse_decimal(value)?;
+
+        Some(Self {
+            minor_units: i64::try_from(mantissa).ok()?,
+            decimals: scale,
+        })
+    }
+
+    /// Converts a decimal from the function input or the config metafield.
+    ///
+    /// Uses the shortest representation of the `f64` (what Shopify sent), so
+    /// `19.99` becomes exactly 1999 cents. Non-finite values count as zero.
+    pub fn from_decimal(amount: f64, currency_code: &str) -> Self {
+        Self::from_converted(amount, 1.0, currency_code)
+    }
+
+    /// Converts `amount` at `rate`, rounding once after the exact product.
+    ///
+    /// Non-finite values count as zero.
+    pub fn from_converted(amount: f64, rate: f64, currency_code: &str) -> Self {
+        let decimals = decimals(currency_code);
+
+        let minor_units = parse_f64(amount).zip(parse_f64(rate)).map_or(
+            0,
+            |((amount, amount_scale), (rate, rate_scale))| {
+                let product = amount
+                    .checked_mul(rate)
+                    .unwrap_or_else(|| saturate(amount, rate));
+                round_half_up(product, amount_scale + rate_scale, decimals)
+                    .unwrap_or_else(|| saturate(product, 1))
+            },
+        );
+
+        Self {
+            minor_units: saturating_i64(minor_units),
+            decimals,
+        }
+    }
+
+    /// Converts this amount to `currency_code` at `rate`, rounding once after
+    /// the exact product.
+    ///
+    /// A non-finite rate counts as zero.
+    pub fn convert(self, rate: f64, currency_code: &str) -> Self {
+        let decimals = decimals(currency_code);
+        let amount = i128::from(self.minor_units);
+
+        let minor_units = parse_f64(rate).map_or(0, |(rate, rate_scale)| {
+            let product = amount
+                .checked_mul(rate)
+                .unwrap_or_else(|| saturate(amount, rate));
+            round_half_up(product, self.decimals + rate_scale, decimals)
+                .unwrap_or_else(|| saturate(product, 1))
+        });
+
+        Self {
+            minor_units: saturating_i64(minor_units),
+            decimals,
+        }
+    }
+
+    /// `percentage`% of this amount, e.g. `percentage(15.0)`.
+    pub fn percentage(self, percentage: f64) -> Self {
+        let minor_units = parse_f64(percentage)
+            .and_then(|(percentage, scale)| {
+                let product = i128::from(self.minor_units)
+                    .checked_mul(percentage)
+                    .unwrap_or_else(|| saturate(i128::from(self.minor_units), percentage));
+                round_half_up(product, scale + 2, 0)
+            })
+            .map_or(0, saturating_i64);
+
+        Self {
+            minor_units,
+            decimals: self.decimals,
+        }
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.minor_units > 0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.minor_units < 0
+    }
+
+    /// The amount in minor units (cents for USD).
+    pub fn minor_units(self) -> i64 {
+        self.minor_units
+    }
+
+    /// Number of decimal places of the currency.
+    pub fn decimals(self) -> u32 {
+        self.decimals
+    }
+
+    pub fn to_decimal(self) -> Decimal {
+        // The nearest f64 to an exact decimal serializes back to that decimal
+        Decimal::from(self.minor_units as f64 / 10f64.powi(self.decimals as i32))
+    }
+}
+
+impl PartialOrd for Money {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Money {
+    fn cmp(&self, other: &Self) -> Ordering {
+        debug_assert_eq!(self.decimals, other.decimals);
+        self.minor_units.cmp(&other.minor_units)
+    }
+}
+
+impl Add for Money {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        debug_assert_eq!(self.decimals, other.decimals);
+        Self {
+            minor_units: self.minor_units.saturating_add(other.minor_units),
+            decimals: self.decimals,
+        }
+    }
+}
+
+impl Sub for Money {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        debug_assert_eq!(self.decimals, other.decimals);
+        Self {
+            minor_units: self.minor_units.saturating_sub(other.minor_units),
+            decimals: self.decimals,
+        }
+    }
+}
+
+fn decimals(currency_code: &str) -> u32 {
+    minor_units(currency_code) as u32
+}
+
+/// Splits a decimal string into an integer mantissa and a scale (the number
+/// of fractional digits), e.g. `"-19.990"` into `(-19990, 3)`.
+fn parse_decimal(value: &str) -> Option<(i128, u32)> {
+    let value = value.trim();
+
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+
+    let (integer, fraction) = match digits.split_once('.') {
+        Some((integer, fraction)) => (integer, fraction),
+        None => (digits, ""),
+    };
+
+    if (integer.is_empty() && fraction.is_empty())
+        || !integer.bytes().all(|b| b.is_ascii_digit())
+        || !fraction.bytes().all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let integer = integer.trim_start_matches('0');
+    let fraction = &fraction[..fraction.len().min(MAX_FRACTION_DIGITS)];
+
+    if integer.len() > MAX_INTEGER_DIGITS {
+        return None;
+    }
+
+    let mantissa = integer
+        .bytes()
+        .chain(fraction.bytes())
+        .fold(0i128, |mantissa, digit| {
+            mantissa * 10 + i128::from(digit - b'0')
+        });
+
+    Some((
+        if negative { -mantissa } else { mantissa },
+        fraction.len() as u32,
+    ))
+}
+
+/// Rescales `mantissa / 10^scale` to `decimals` places, rounding half-up
+/// (away from zero).
+fn round_half_up(mantissa: i128, scale: u32, decimals: u32) -> Option<i128> {
+    if scale <= decimals {
+        return mantissa.checked_mul(10i128.checked_pow(decimals - scale)?);
+    }
+
+    let divisor = match 10i128.checked_pow(scale - decimals) {
+        Some(divisor) => divisor,
+        // Too small to reach the first kept digit
+        None => return Some(0),
+    };
+
+    let quotient = mantissa / divisor;
+    let remainder = (mantissa % divisor).abs();
+
+    if remainder >= divisor - remainder {
+        Some(quotient + mantissa.signum())
+    } else {
+        Some(quotient)
+    }
+}
+
+/// `parse_decimal` for an `f64`, or `None` when it isn't finite.
+fn parse_f64(value: f64) -> Option<(i128, u32)> {
+    if !value.is_finite() {
+        return None;
+    }
+
+    // Far beyond any amount of minor units an i64 holds, so saturates just the same
+    parse_decimal(&value.clamp(-1e23, 1e23).to_string())
+}
+
+fn saturate(a: i128, b: i128) -> i128 {
+    if (a < 0) == (b < 0) {
+        i128::MAX
+    } else {
+        i128::MIN
+    }
+}
+
+fn saturating_i64(value: i128) -> i64 {
+    value.clamp(i128::from(i64::MIN), i128::from(i64::MAX)) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::format_money;
+    use proptest::prelude::*;
+
+    fn usd(minor_units: i64) -> Money {
+        Money {
+            minor_units,
+            decimals: 2,
+        }
+    }
+
+    #[test]
+    fn rounds_half_up_to_minor_units() {
+        assert_eq!(usd(1999).percentage(15.0), usd(300));
+        assert_eq!(usd(10000) - usd(8001), usd(1999));
+        assert_eq!(Money::parse("0.125", "USD"), Some(usd(13)));
+        assert_eq!(Money::parse("-0.125", "USD"), Some(usd(-13)));
+        assert_eq!(Money::parse("0.124", "USD"), Some(usd(12)));
+        assert_eq!(
+            Money::parse("1000.5", "JPY").map(Money::minor_units),
+            Some(1001)
+        );
+        assert_eq!(
+            Money::parse("1.2345", "KWD").map(Money::minor_units),
+            Some(1235)
+        );
+        assert_eq!(Money::from_converted(10.0, 0.9, "EUR"), usd(900));
+        assert_eq!(Money::from_converted(19.99, 1.005, "USD"), usd(2009));
+    }
+
+    #[test]
+    fn uses_each_currencys_minor_units() {
+        assert_eq!(Money::zero("JPY").decimals(), 0);
+        assert_eq!(Money::zero("USD").decimals(), 2);
+        for currency_code in ["BHD", "IQD", "JOD", "KWD", "LYD", "OMR", "TND"] {
+            assert_eq!(Money::zero(currency_code).decimals(), 3, "{currency_code}");
+        }
+        assert_eq!(
+            format_money(Money::from_decimal(12.5, "KWD"), "KWD"),
+            "12.500 KWD"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_amounts() {
+        for value in ["", "-", ".", "5,00", "NaN", "inf", "1e3", "12.3.4", "$5"] {
+            assert_eq!(Money::parse(value, "USD"), None, "{value}");
+            assert_eq!(Money::parse_exact(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn converts_exact_amounts_once() {
+        let amount = Money::parse_exact("10.005").unwrap();
+        assert_eq!(amount.convert(1.0, "USD"), usd(1001));
+        assert_eq!(amount.convert(0.9, "EUR"), usd(900));
+        assert_eq!(amount.convert(150.0, "JPY").minor_units(), 1501);
+        assert_eq!(
+            Money::parse_exact("19.99").unwrap().convert(1.005, "USD"),
+            usd(2009)
+        );
+        assert_eq!(amount.convert(f64::NAN, "USD"), usd(0));
+    }
+
+    proptest! {
+        #[test]
+        fn decimals_round_trip_exactly(minor_units in -1_000_000_000_000i64..1_000_000_000_000) {
+            let amount = minor_units as f64 / 100.0;
+            prop_assert_eq!(Money::from_decimal(amount, "USD"), usd(minor_units));
+            prop_assert_eq!(usd(minor_units).to_decimal().as_f64(), amount);
+        }
+
+        #[test]
+        fn formatted_amounts_parse_back(minor_units in -1_000_000_000_000i64..1_000_000_000_000) {
+            let formatted = format_money(usd(minor_units), "XYZ");
+            let number = formatted.trim_end_matches(" XYZ");
+            prop_assert_eq!(Money::parse(number, "USD"), Some(usd(minor_units)));
+        }
+
+        #[test]
+        fn percentage_matches_integer_half_up(
+            minor_units in 0i64..1_000_000_000_000,
+            percentage in 0i64..=100,
+        ) {
+            let expected = (minor_units * percentage + 50) / 100;
+            prop_assert_eq!(usd(minor_units).percentage(percentage as f64), usd(expected));
+        }
+
+        #[test]
+        fn percentage_stays_within_amount(
+            minor_units in 0i64..1_000_000_000_000,
+            percentage in 0.0f64..=100.0,
+        ) {
+            let discount = usd(minor_units).percentage(percentage);
+            prop_assert!(!discount.is_negative());
+            prop_assert!(discount <= usd(minor_units));
+        }
+
+        #[test]
+        fn subtraction_undoes_addition(a in -1_000_000_000_000i64..1_000_000_000_000, b in -1_000_000_000_000i64..1_000_000_000_000) {
+            prop_assert_eq!(usd(a) + usd(b) - usd(b), usd(a));
+        }
+
+        #[test]
+        fn conversion_at_par_is_identity(minor_units in -1_000_000_000_000i64..1_000_000_000_000) {
+            let amount = minor_units as f64 / 100.0;
+            prop_assert_eq!(Money::from_converted(amount, 1.0, "USD"), usd(minor_units));
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use crate::cart_lines_discounts_generate_run::{DiscountConfig, QueryVariables};
+use crate::referral_token::{verify_referral_token, ReferralClaims, ReferralTokenError};
+use crate::schema;
+use crate::self_referral::is_self_referral;
+use std::fmt;
+
+/// The referral details of a cart, read from either discount target's input.
+pub struct Referral<'a> {
+    /// The shop's local date
+    pub today: &'a str,
+    // Cart attributes set by the cart block
+    pub referral_validated: bool,
+    pub referrer_customer_id: Option<&'a str>,
+    pub referral_token: Option<&'a str>,
+    pub referrer_order_count: Option<&'a str>,
+    pub referred_on: Option<&'a str>,
+    pub referral_program: Option<&'a str>,
+    /// The discount's `referral_signing_key` metafield
+    pub signing_key: Option<&'a str>,
+    // The buyer
+    pub customer_id: Option<&'a str>,
+    pub email: Option<&'a str>,
+    pub number_of_orders: i32,
+    pub has_redeemed_referral: bool,
+}
+
+// Both targets query the referral the same way, so their inputs have the same accessors
+macro_rules! referral_from_input {
+    ($($input:ty),+) => {$(
+        impl<'a> From<&'a $input> for Referral<'a> {
+            fn from(input: &'a $input) -> Self {
+                let cart = input.cart();
+                let buyer_identity = cart.buyer_identity();
+                let customer = buyer_identity.and_then(|identity| identity.customer());
+
+                Self {
+                    today: input.shop().local_time().date(),
+                    referral_validated: cart
+                        .referral_validated()
+                        .and_then(|attr| attr.value())
+                        .is_some_and(|v| v == "true"),
+                    referrer_customer_id: cart
+                        .referrer_customer_id()
+                        .and_then(|attr| attr.value())
+                        .map(|v| v.as_str()),
+                    referral_token: cart
+                        .referral_token()
+                        .and_then(|attr| attr.value())
+                        .map(|v| v.as_str()),
+                    referrer_order_count: cart
+                        .referrer_order_count()
+                        .and_then(|attr| attr.value())
+                        .map(|v| v.as_str()),
+                    referred_on: cart
+                        .referred_on()
+                        .and_then(|attr| attr.value())
+                        .map(|v| v.as_str()),
+                    referral_program: cart
+                        .referral_program()
+                        .and_then(|attr| attr.value())
+                        .map(|v| v.as_str()),
+                    signing_key: input
+                        .discount()
+                        .referral_signing_key()
+                        .map(|metafield| metafield.value().as_str()),
+                    customer_id: customer.map(|customer| customer.id().as_str()),
+                    email: buyer_identity
+                        .and_then(|identity| identity.email())
+                        .map(|email| email.as_str()),
+                    number_of_orders: customer.map_or(0, |customer| *customer.number_of_orders()),
+                    has_redeemed_referral: customer
+                        .and_then(|customer| customer.used_referral())
+                        .is_some_and(|m| m.json_value().used),
+                }
+            }
+        }
+    )+};
+}
+
+referral_from_input!(
+    schema::cart_lines_discounts_generate_run::Input,
+    schema::cart_delivery_options_discounts_generate_run::Input
+);
+
+/// Why a buyer doesn't get the referee discount.
+#[derive(Debug, PartialEq)]
+pub enum RefereeRejection {
+    /// The cart has no validated referral
+    NoReferral,
+    InvalidToken(ReferralTokenError),
+    /// The `referral_program` attribute isn't the program the token was signed for
+    ProgramMismatch,
+    SelfReferral,
+    ReferrerTooFewOrders,
+    ReturningCustomer,
+    NotYetAvailable,
+}
+
+impl fmt::Display for RefereeRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoReferral => f.write_str("no validated referral"),
+            Self::InvalidToken(error) => error.fmt(f),
+            Self::ProgramMismatch => f.write_str("referral program doesn't match the token"),
+            Self::SelfReferral => f.write_str("buyer is the referrer"),
+            Self::ReferrerTooFewOrders => f.write_str("referrer has too few orders"),
+            Self::ReturningCustomer => f.write_str("buyer is a returning customer"),
+            Self::NotYetAvailable => f.write_str("referee discount isn't available yet"),
+        }
+    }
+}
+
+/// The config that applies to this cart, or `None` while the campaign is off.
+///
+/// Influencer, employee and customer programs can each have their own
+/// settings, selected by the `referral_program` cart attribute, and campaigns
+/// switch themselves on and off on their schedule. The hour window is the
+/// top-level one for every program, as the input query checks only one.
+pub fn running_program<'a>(
+    config: &'a DiscountConfig,
+    referral_program: Option<&str>,
+    today: &str,
+    query_variables: Option<&QueryVariables>,
+    in_time_window: impl FnOnce() -> bool,
+) -> Option<&'a DiscountConfig> {
+    let program = config.program(referral_program);
+
+    (program.is_running(today) && config.in_active_hours(query_variables, in_time_window))
+        .then_some(program)
+}
+
+/// Checks that the buyer may receive the referee discount.
+///
+/// The referral link names the referrer in cart attributes set by the cart
+/// block.
+pub fn check_referee(config: &DiscountConfig, referral: &Referral) -> Result<(), RefereeRejection> {
+    let (referrer_id, claims) = attribute_referrer(config, referral)?;
+
+    // The program picks the settings, so it has to be the one the referrer was
+    // signed up for
+    if let Some(claims) = &claims {
+        let referral_program = referral
+            .referral_program
+            .map(str::trim)
+            .filter(|program| !program.is_empty());
+
+        if referral_program != claims.referral_program {
+            return Err(RefereeRejection::ProgramMismatch);
+        }
+    }
+
+    // Customers can't redeem their own referral link. Guests are matched by
+    // the referrer's email hash, which only a signed token carries
+    if is_self_referral(
+        referral.customer_id,
+        referral.email,
+        referrer_id,
+        claims
+            .as_ref()
+            .and_then(|claims| claims.referrer_email_hash),
+    ) {
+        return Err(RefereeRejection::SelfReferral);
+    }
+
+    // Referrers need `min_referrer_orders` orders before their links pay out.
+    // A signed token carries the count; the attribute is only trusted without one
+    let referrer_order_count = match &claims {
+        Some(claims) => Some(claims.referrer_order_count),
+        None => referral.referrer_order_count,
+    };
+
+    if !config.referrer_qualifies(referrer_order_count) {
+        return Err(RefereeRejection::ReferrerTooFewOrders);
+    }
+
+    // Referee discounts are for first orders unless the merchant allows returning customers
+    if !config.allows_referee(referral.number_of_orders, referral.has_redeemed_referral) {
+        return Err(RefereeRejection::ReturningCustomer);
+    }
+
+    // Merchants can hold the referee discount back for a number of days after
+    // the referral; a signed token carries the date the app proxy issued it
+    let referred_on = match &claims {
+        Some(claims) => Some(claims.referred_on),
+        None => referral.referred_on,
+    };
+
+    if !config.referee_available(referred_on, referral.today) {
+        return Err(RefereeRejection::NotYetAvailable);
+    }
+
+    Ok(())
+}
+
+/// The referrer named by the link's cart attributes, once the referral is
+/// validated and, with a signing key configured, its token checks out.
+fn attribute_referrer<'a>(
+    config: &DiscountConfig,
+    referral: &Referral<'a>,
+) -> Result<(&'a str, Option<ReferralClaims<'a>>), RefereeRejection> {
+    if !config.accepts_referral(referral.referral_validated) {
+        return Err(RefereeRejection::NoReferral);
+    }
+
+    let referrer_id = referral
+        .referrer_customer_id
+        .ok_or(RefereeRejection::NoReferral)?;
+
+    // Cart attributes can be set by anyone, so once a signing key is
+    // configured the referrer must come with a valid signed token
+    let claims = match referral.signing_key {
+        Some(signing_key) => Some(
+            verify_referral_token(
+                referral.referral_token,
+                referrer_id,
+                signing_key,
+                referral.today,
+            )
+            .map_err(RefereeRejection::InvalidToken)?,
+        ),
+        None => None,
+    };
+
+    Ok((referrer_id, claims))
+}
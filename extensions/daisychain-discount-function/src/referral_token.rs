@@ -0,0 +1,129 @@
+use crate::dates::is_iso_date;
+use crate::hex;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use std::fmt;
+
+/// Why a `referral_token` cart attribute was rejected.
+#[derive(Debug, PartialEq)]
+pub enum ReferralTokenError {
+    Missing,
+    Malformed,
+    Expired,
+    InvalidSignature,
+}
+
+impl fmt::Display for ReferralTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Self::Missing => "missing referral token",
+            Self::Malformed => "malformed referral token",
+            Self::Expired => "expired referral token",
+            Self::InvalidSignature => "invalid referral token signature",
+        };
+
+        f.write_str(reason)
+    }
+}
+
+/// What a valid `referral_token` vouches for besides the referrer.
+#[derive(Debug, PartialEq)]
+pub struct ReferralClaims<'a> {
+    /// The referrer's order count when the app proxy looked them up
+    pub referrer_order_count: &'a str,
+    /// The shop's local date when the referral was entered
+    pub referred_on: &'a str,
+    /// Hex SHA-256 of the referrer's trimmed, lowercased email (None when
+    /// they have no email)
+    pub referrer_email_hash: Option<&'a str>,
+    /// The referrer's referral program (None for the top-level settings)
+    pub referral_program: Option<&'a str>,
+}
+
+/// Verifies the signed `referral_token` cart attribute.
+///
+/// The app proxy signs the referrer it looks up (`app/lib/referral-token.ts`)
+/// and the cart block stores the token next to `referrer_customer_id`. The
+/// key is created with the discount, or on the first lookup for older ones.
+///
+/// The token has the form
+/// `<expires_on>.<referrer_order_count>.<referred_on>.<referrer_email_hash>.<referral_program>.<signature>`,
+/// where `expires_on` and `referred_on` are ISO dates in the shop's time zone
+/// (`2025-01-31`; the token is valid through `expires_on`),
+/// `referrer_email_hash` is empty for referrers without an email,
+/// `referral_program` is empty for referrers outside a named program, and
+/// `signature` is the hex-encoded HMAC-SHA256 of
+/// `<referrer_customer_id>|<expires_on>|<referrer_order_count>|<referred_on>|<referrer_email_hash>|<referral_program>`
+/// under the signing key stored in the discount's `referral_signing_key`
+/// metafield.
+pub fn verify_referral_token<'a>(
+    token: Option<&'a str>,
+    referrer_customer_id: &str,
+    signing_key: &str,
+    today: &str,
+) -> Result<ReferralClaims<'a>, ReferralTokenError> {
+    let token = token.ok_or(ReferralTokenError::Missing)?;
+
+    let mut parts = token.splitn(5, '.');
+    let (
+        Some(expires_on),
+        Some(referrer_order_count),
+        Some(referred_on),
+        Some(referrer_email_hash),
+        Some(rest),
+    ) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    )
+    else {
+        return Err(ReferralTokenError::Malformed);
+    };
+
+    // Program names may contain dots, the signature never does
+    let (referral_program, signature) =
+        rest.rsplit_once('.').ok_or(ReferralTokenError::Malformed)?;
+
+    if !is_iso_date(expires_on)
+        || !is_iso_date(referred_on)
+        || referrer_order_count.is_empty()
+        || !referrer_order_count.bytes().all(|b| b.is_ascii_digit())
+        || !(referrer_email_hash.is_empty() || hex::decode(referrer_email_hash).is_some())
+    {
+        return Err(ReferralTokenError::Malformed);
+    }
+
+    let signature = hex::decode(signature).ok_or(ReferralTokenError::Malformed)?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key.as_bytes())
+        .map_err(|_| ReferralTokenError::InvalidSignature)?;
+    mac.update(referrer_customer_id.as_bytes());
+    mac.update(b"|");
+    mac.update(expires_on.as_bytes());
+    mac.update(b"|");
+    mac.update(referrer_order_count.as_bytes());
+    mac.update(b"|");
+    mac.update(referred_on.as_bytes());
+    mac.update(b"|");
+    mac.update(referrer_email_hash.as_bytes());
+    mac.update(b"|");
+    mac.update(referral_program.as_bytes());
+
+    // Constant-time comparison
+    mac.verify_slice(&signature)
+        .map_err(|_| ReferralTokenError::InvalidSignature)?;
+
+    // ISO dates compare correctly as strings
+    if today > expires_on {
+        return Err(ReferralTokenError::Expired);
+    }
+
+    Ok(ReferralClaims {
+        referrer_order_count,
+        referred_on,
+        referrer_email_hash: Some(referrer_email_hash).filter(|hash| !hash.is_empty()),
+        referral_program: Some(referral_program).filter(|program| !program.is_empty()),
+    })
+}
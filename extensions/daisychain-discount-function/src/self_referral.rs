@@ -0,0 +1,28 @@
+use crate::hex;
+use sha2::{Digest, Sha256};
+
+/// Whether the buyer is the referrer named in the cart attributes.
+///
+/// Matches the logged-in customer's GID against `referrer_customer_id`, and the
+/// buyer's email against the referrer's email hash signed into
+/// `referral_token` (hex SHA-256 of their trimmed, lowercased email) so guest
+/// checkouts are caught too.
+pub fn is_self_referral(
+    buyer_customer_id: Option<&str>,
+    buyer_email: Option<&str>,
+    referrer_customer_id: &str,
+    referrer_email_hash: Option<&str>,
+) -> bool {
+    let same_customer = buyer_customer_id == Some(referrer_customer_id);
+
+    let same_email = match (buyer_email, referrer_email_hash) {
+        (Some(email), Some(hash)) => email_hash(email).eq_ignore_ascii_case(hash.trim()),
+        _ => false,
+    };
+
+    same_customer || same_email
+}
+
+fn email_hash(email: &str) -> String {
+    hex::encode(&Sha256::digest(email.trim().to_lowercase().as_bytes()))
+}
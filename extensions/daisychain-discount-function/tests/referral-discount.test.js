@@ -27,6 +27,12 @@ describe("Referral Discount Function Tests", () => {
       shouldApplyDiscount: true,
       expectedDiscountPercentage: 10.0
     },
+    {
+      name: "applies the highest qualifying tier",
+      fixture: "referral-tiered.json",
+      shouldApplyDiscount: true,
+      expectedDiscountPercentage: 15.0
+    },
     {
       name: "does not apply discount when referral is not validated",
       fixture: "referral-not-validated.json",
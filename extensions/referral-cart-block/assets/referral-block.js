@@ -54,6 +54,10 @@
   const checkReferrerBtn = document.getElementById('check-referrer-btn');
   const referrerStatusMessage = document.getElementById('referrer-status-message');
   const duplicatesList = document.getElementById('referral-duplicates-list');
+  // Store credit control, only rendered for logged-in customers
+  const storeCreditInput = document.getElementById('store-credit-amount-input');
+  const storeCreditBtn = document.getElementById('store-credit-apply-btn');
+  const storeCreditMessage = document.getElementById('store-credit-message');
 
   if (!triggerBtn || !modalOverlay || !modalClose || !step1 || !step2 || !step2_5 || !step3 || !step4 || !yesBtn || !noBtn || !continueShoppingBtn || !input || !validateBtn || !message || !successMessage || !checkReferrerInput || !checkReferrerBtn || !referrerStatusMessage || !backBtnDup || !duplicatesList) return;
 
@@ -73,6 +77,38 @@
     text: "#ffffff",
   };
   
+  /**
+   * Signed referral token for a referrer, reusing the one stored when the same
+   * referrer was entered before so entering them again doesn't restart the
+   * referee wait. Format:
+   * <expires_on>.<order_count>.<referred_on>.<email_hash>.<referral_program>.<signature>
+   */
+  function referralTokenFor(referrerId, issuedToken) {
+    try {
+      const stored = JSON.parse(localStorage.getItem('daisychain_referral_token') || 'null');
+      const today = new Date().toISOString().slice(0, 10);
+      const parts = stored ? stored.token.split('.') : [];
+      // Tokens stored before the current format no longer verify
+      if (stored && stored.referrerId === referrerId && parts.length >= 6 && parts[0] >= today) {
+        return stored.token;
+      }
+    } catch (e) {
+      // Unreadable entry, replaced below
+    }
+
+    if (issuedToken) {
+      localStorage.setItem('daisychain_referral_token', JSON.stringify({ referrerId, token: issuedToken }));
+    }
+    return issuedToken || null;
+  }
+
+  /**
+   * The referral program a token was signed for (program names may contain dots)
+   */
+  function tokenProgram(referralToken) {
+    return referralToken.split('.').slice(4, -1).join('.');
+  }
+
   // Track if referral has been validated
   let isReferralValidated = false;
   let validatedReferrerName = '';
@@ -595,6 +631,8 @@
 
     try {
       // Update cart with selected customer
+      const referralToken = referralTokenFor(customer.id, customer.referralToken);
+
       const cartUpdateResponse = await fetch('/cart/update.js', {
         method: 'POST',
         headers: {
@@ -605,6 +643,16 @@
             referral_validated: 'true',
             referrer_customer_id: customer.id,
             referrer_name: customer.displayName,
+            // Signed by the app proxy; required once the discount has a signing key
+            ...(referralToken ? { referral_token: referralToken } : {}),
+            // Only trusted without a signing key; referral_token carries the signed count
+            referrer_order_count: String(customer.numberOfOrders || 0),
+            // Starts the referee_available_after_days wait: the shop date signed into
+            // referral_token, or today's UTC date when the discount has no signing key
+            referred_on: referralToken ? referralToken.split('.')[2] : new Date().toISOString().slice(0, 10),
+            // Selects one of the config's named programs: the referrer's, as signed
+            // into referral_token (the discount function rejects any other)
+            referral_program: referralToken ? tokenProgram(referralToken) : (customer.referralProgram || ''),
           },
         }),
       });
@@ -738,6 +786,8 @@
       // This sets attributes on the Online Store cart (not Storefront API cart)
       // The discount function reads from the Online Store cart at checkout
       try {
+        const referralToken = referralTokenFor(lookupData.customer.id, lookupData.customer.referralToken);
+
         const cartUpdateResponse = await fetch('/cart/update.js', {
           method: 'POST',
           headers: {
@@ -748,6 +798,18 @@
               referral_validated: 'true',
               referrer_customer_id: lookupData.customer.id,
               referrer_name: lookupData.customer.displayName,
+              // Signed by the app proxy; required once the discount has a signing key
+              ...(referralToken ? { referral_token: referralToken } : {}),
+              // Only trusted without a signing key; referral_token carries the signed count
+              referrer_order_count: String(lookupData.customer.numberOfOrders || 0),
+              // Starts the referee_available_after_days wait: the shop date signed into
+              // referral_token, or today's UTC date when the discount has no signing key
+              referred_on: referralToken ? referralToken.split('.')[2] : new Date().toISOString().slice(0, 10),
+              // Selects one of the config's named programs: the referrer's, as signed
+              // into referral_token (the discount function rejects any other)
+              referral_program: referralToken
+                ? tokenProgram(referralToken)
+                : (lookupData.customer.referralProgram || ''),
             },
           }),
         });
@@ -977,6 +1039,73 @@
     referrerStatusMessage.style.display = 'none';
   }
 
+  /**
+   * Save how much store credit to use on this order
+   * Sets the store_credit_requested cart attribute read by the discount function
+   * (an empty amount removes it, so the full balance is used)
+   */
+  async function saveStoreCreditRequest() {
+    const amount = storeCreditInput.value.trim();
+
+    if (amount && !/^\d+(\.\d+)?$/.test(amount)) {
+      showStoreCreditMessage('Please enter an amount such as 10.00', true);
+      return;
+    }
+
+    storeCreditBtn.disabled = true;
+    storeCreditMessage.style.display = 'none';
+
+    try {
+      const cartUpdateResponse = await fetch('/cart/update.js', {
+        method: 'POST',
+        headers: {
+          'Content-Type': 'application/json',
+        },
+        body: JSON.stringify({
+          attributes: {
+            store_credit_requested: amount,
+          },
+        }),
+      });
+
+      if (!cartUpdateResponse.ok) {
+        throw new Error('Cart update failed');
+      }
+
+      showStoreCreditMessage(
+        amount
+          ? `Up to ${amount} of your store credit will be applied at checkout.`
+          : 'Your full store credit balance will be applied at checkout.',
+        false
+      );
+    } catch (error) {
+      console.error('Daisychain: Failed to save store credit request', error);
+      showStoreCreditMessage('Failed to save. Please try again.', true);
+    } finally {
+      storeCreditBtn.disabled = false;
+    }
+  }
+
+  /**
+   * Show store credit message
+   */
+  function showStoreCreditMessage(text, isError = false) {
+    storeCreditMessage.textContent = text;
+    storeCreditMessage.className = `referral-message ${isError ? 'error' : 'success'}`;
+    storeCreditMessage.style.display = 'block';
+  }
+
+  // Store credit control (only rendered for logged-in customers)
+  if (storeCreditInput && storeCreditBtn && storeCreditMessage) {
+    storeCreditBtn.addEventListener('click', saveStoreCreditRequest);
+    storeCreditInput.addEventListener('keypress', (e) => {
+      if (e.key === 'Enter') {
+        e.preventDefault();
+        saveStoreCreditRequest();
+      }
+    });
+  }
+
   // Check referrer status button
   checkReferrerBtn.addEventListener('click', checkReferrerStatus);
 